@@ -0,0 +1,277 @@
+// Layer blend modes, after the W3C Compositing and Blending Level 1 spec.
+//
+// `blend` is the spec's B(Cb, Cs): what the layer (source, Cs) shows over an
+// opaque backdrop (Cb), on straight RGB in 0..1. `composite` mixes that in
+// where the backdrop is opaque and does source-over, on premultiplied RGBA.
+// Add and Subtract are not in the spec; they are the usual linear dodge and
+// its inverse.
+
+use app::canvas::{BlendMode, saturate};
+
+// In the order the layers panel lists them.
+pub const MODES: [(BlendMode, &'static str); 18] = [
+    (BlendMode::Normal, "normal"),
+    (BlendMode::Multiply, "multiply"),
+    (BlendMode::Screen, "screen"),
+    (BlendMode::Overlay, "overlay"),
+    (BlendMode::Darken, "darken"),
+    (BlendMode::Lighten, "lighten"),
+    (BlendMode::ColorDodge, "color dodge"),
+    (BlendMode::ColorBurn, "color burn"),
+    (BlendMode::HardLight, "hard light"),
+    (BlendMode::SoftLight, "soft light"),
+    (BlendMode::Difference, "difference"),
+    (BlendMode::Exclusion, "exclusion"),
+    (BlendMode::Add, "add"),
+    (BlendMode::Subtract, "subtract"),
+    (BlendMode::Hue, "hue"),
+    (BlendMode::Saturation, "saturation"),
+    (BlendMode::Color, "color"),
+    (BlendMode::Luminosity, "luminosity"),
+];
+
+fn multiply(cb: f64, cs: f64) -> f64 {
+    cb * cs
+}
+
+fn screen(cb: f64, cs: f64) -> f64 {
+    cb + cs - cb * cs
+}
+
+fn hard_light(cb: f64, cs: f64) -> f64 {
+    if cs <= 0.5 {
+        multiply(cb, 2.0 * cs)
+    } else {
+        screen(cb, 2.0 * cs - 1.0)
+    }
+}
+
+fn soft_light(cb: f64, cs: f64) -> f64 {
+    if cs <= 0.5 {
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+    } else {
+        let d = if cb <= 0.25 { ((16.0 * cb - 12.0) * cb + 4.0) * cb } else { cb.sqrt() };
+        cb + (2.0 * cs - 1.0) * (d - cb)
+    }
+}
+
+// One channel of the modes that treat the channels separately.
+fn separable(mode: BlendMode, cb: f64, cs: f64) -> f64 {
+    match mode {
+        BlendMode::Multiply => multiply(cb, cs),
+        BlendMode::Screen => screen(cb, cs),
+        BlendMode::Overlay => hard_light(cs, cb),
+        BlendMode::Darken => cb.min(cs),
+        BlendMode::Lighten => cb.max(cs),
+        BlendMode::ColorDodge => {
+            if cb == 0.0 {
+                0.0
+            } else if cs >= 1.0 {
+                1.0
+            } else {
+                (cb / (1.0 - cs)).min(1.0)
+            }
+        },
+        BlendMode::ColorBurn => {
+            if cb >= 1.0 {
+                1.0
+            } else if cs == 0.0 {
+                0.0
+            } else {
+                1.0 - ((1.0 - cb) / cs).min(1.0)
+            }
+        },
+        BlendMode::HardLight => hard_light(cb, cs),
+        BlendMode::SoftLight => soft_light(cb, cs),
+        BlendMode::Difference => (cb - cs).abs(),
+        BlendMode::Exclusion => cb + cs - 2.0 * cb * cs,
+        BlendMode::Add => (cb + cs).min(1.0),
+        BlendMode::Subtract => (cb - cs).max(0.0),
+        _ => cs,
+    }
+}
+
+fn lum(c: [f64; 3]) -> f64 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+// Pulls the channels back into 0..1 keeping the luminosity.
+fn clip_color(c: [f64; 3]) -> [f64; 3] {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut c = c;
+    for v in &mut c {
+        if n < 0.0 {
+            *v = l + (*v - l) * l / (l - n);
+        }
+        if x > 1.0 {
+            *v = l + (*v - l) * (1.0 - l) / (x - l);
+        }
+    }
+    c
+}
+
+fn set_lum(c: [f64; 3], l: f64) -> [f64; 3] {
+    let d = l - lum(c);
+    clip_color([c[0] + d, c[1] + d, c[2] + d])
+}
+
+fn sat(c: [f64; 3]) -> f64 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_sat(c: [f64; 3], s: f64) -> [f64; 3] {
+    // indices of the smallest, middle and largest channel
+    let mut order = [0, 1, 2];
+    order.sort_by(|&i, &j| c[i].partial_cmp(&c[j]).unwrap_or(::std::cmp::Ordering::Equal));
+    let (min, mid, max) = (order[0], order[1], order[2]);
+    let mut out = [0.0; 3];
+    if c[max] > c[min] {
+        out[mid] = (c[mid] - c[min]) * s / (c[max] - c[min]);
+        out[max] = s;
+    }
+    out
+}
+
+pub fn blend(mode: BlendMode, cb: [f64; 3], cs: [f64; 3]) -> [f64; 3] {
+    match mode {
+        BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+        BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+        BlendMode::Color => set_lum(cs, lum(cb)),
+        BlendMode::Luminosity => set_lum(cb, lum(cs)),
+        _ => [separable(mode, cb[0], cs[0]), separable(mode, cb[1], cs[1]), separable(mode, cb[2], cs[2])],
+    }
+}
+
+fn unpremultiply(c: [f64; 4]) -> [f64; 3] {
+    if c[3] > 0.0 {
+        [c[0] / c[3], c[1] / c[3], c[2] / c[3]]
+    } else {
+        [0.0; 3]
+    }
+}
+
+// The source over the backdrop, both premultiplied RGBA in 0..1:
+// co = cs (1 - ab) + cb (1 - as) + as ab B(Cb, Cs).
+pub fn composite(mode: BlendMode, b: [f64; 4], s: [f64; 4]) -> [f64; 4] {
+    let a_o = s[3] + b[3] * (1.0 - s[3]);
+    let mixed = match mode {
+        BlendMode::Normal => unpremultiply(s),
+        _ => blend(mode, unpremultiply(b), unpremultiply(s)),
+    };
+    let mut c = [0.0, 0.0, 0.0, a_o];
+    for k in 0..3 {
+        let v = s[k] * (1.0 - b[3]) + b[k] * (1.0 - s[3]) + s[3] * b[3] * mixed[k];
+        c[k] = saturate(v, 0.0, a_o);
+    }
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use app::canvas::BlendMode;
+
+    const CB: [f64; 3] = [0.8, 0.4, 0.2];
+    const CS: [f64; 3] = [0.25, 0.5, 0.75];
+
+    fn assert_close(actual: [f64; 3], expected: [f64; 3]) {
+        for k in 0..3 {
+            assert!((actual[k] - expected[k]).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn separable_modes() {
+        let cases = [
+            (BlendMode::Normal, [0.25, 0.5, 0.75]),
+            (BlendMode::Multiply, [0.2, 0.2, 0.15]),
+            (BlendMode::Screen, [0.85, 0.7, 0.8]),
+            (BlendMode::Overlay, [0.7, 0.4, 0.3]),
+            (BlendMode::Darken, [0.25, 0.4, 0.2]),
+            (BlendMode::Lighten, [0.8, 0.5, 0.75]),
+            (BlendMode::ColorDodge, [1.0, 0.8, 0.8]),
+            (BlendMode::ColorBurn, [0.2, 0.0, 0.0]),
+            (BlendMode::HardLight, [0.4, 0.4, 0.6]),
+            (BlendMode::SoftLight, [0.72, 0.4, 0.324]),
+            (BlendMode::Difference, [0.55, 0.1, 0.55]),
+            (BlendMode::Exclusion, [0.65, 0.5, 0.65]),
+            (BlendMode::Add, [1.0, 0.9, 0.95]),
+            (BlendMode::Subtract, [0.55, 0.0, 0.0]),
+        ];
+        for &(mode, expected) in cases.iter() {
+            assert_close(blend(mode, CB, CS), expected);
+        }
+    }
+
+    #[test]
+    fn soft_light_dark_backdrop() {
+        // below a quarter the backdrop goes through the polynomial
+        assert_close(blend(BlendMode::SoftLight, [0.1, 0.1, 0.1], [0.75, 0.75, 0.75]),
+                     [0.198, 0.198, 0.198]);
+    }
+
+    #[test]
+    fn dodge_and_burn_limits() {
+        assert_close(blend(BlendMode::ColorDodge, [0.0, 0.5, 0.5], [1.0, 1.0, 0.0]), [0.0, 1.0, 0.5]);
+        assert_close(blend(BlendMode::ColorBurn, [1.0, 0.5, 0.5], [0.0, 0.0, 1.0]), [1.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn non_separable_modes() {
+        // lum(CB) = 0.498, lum(CS) = 0.4525
+        assert_close(blend(BlendMode::Hue, CB, CS), [0.255, 0.555, 0.855]);
+        assert_close(blend(BlendMode::Saturation, CB, CS), [0.7496666666666667, 0.41633333333333333, 0.24966666666666668]);
+        assert_close(blend(BlendMode::Color, CB, CS), [0.2955, 0.5455, 0.7955]);
+        assert_close(blend(BlendMode::Luminosity, CB, CS), [0.7545, 0.3545, 0.1545]);
+    }
+
+    #[test]
+    fn color_is_clipped_keeping_luminosity() {
+        // pure blue at the luminosity of white does not fit, so it is pulled to white
+        assert_close(blend(BlendMode::Color, [1.0, 1.0, 1.0], [0.0, 0.0, 1.0]), [1.0, 1.0, 1.0]);
+        let c = blend(BlendMode::Luminosity, [0.0, 0.0, 1.0], [0.9, 0.9, 0.9]);
+        assert!((lum(c) - 0.9).abs() < 1e-9);
+        assert!(c.iter().all(|&v| v >= 0.0 && v <= 1.0));
+    }
+
+    fn assert_close4(actual: [f64; 4], expected: [f64; 4]) {
+        for k in 0..4 {
+            assert!((actual[k] - expected[k]).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    fn premultiplied(c: [f64; 3], a: f64) -> [f64; 4] {
+        [c[0] * a, c[1] * a, c[2] * a, a]
+    }
+
+    #[test]
+    fn source_over() {
+        // half red over opaque blue
+        assert_close4(composite(BlendMode::Normal, [0.0, 0.0, 1.0, 1.0], [0.5, 0.0, 0.0, 0.5]),
+                      [0.5, 0.0, 0.5, 1.0]);
+        // half red over half blue: straight (2/3, 0, 1/3) at 0.75
+        assert_close4(composite(BlendMode::Normal, [0.0, 0.0, 0.5, 0.5], [0.5, 0.0, 0.0, 0.5]),
+                      [0.5, 0.0, 0.25, 0.75]);
+        // a quarter of white over nothing stays white, only fainter
+        assert_close4(composite(BlendMode::Normal, [0.0; 4], [0.25, 0.25, 0.25, 0.25]),
+                      [0.25, 0.25, 0.25, 0.25]);
+        // fully transparent source leaves the backdrop alone
+        assert_close4(composite(BlendMode::Normal, [0.2, 0.3, 0.1, 0.6], [0.0; 4]), [0.2, 0.3, 0.1, 0.6]);
+    }
+
+    #[test]
+    fn composite_over_opaque_and_transparent_backdrop() {
+        let c = composite(BlendMode::Multiply, premultiplied(CB, 1.0), premultiplied(CS, 0.5));
+        assert_close4(c, [0.5, 0.3, 0.175, 1.0]);
+        // nothing below, so the source shows unblended
+        let c = composite(BlendMode::Multiply, [0.0; 4], premultiplied(CS, 0.5));
+        assert_close4(c, premultiplied(CS, 0.5));
+        let c = composite(BlendMode::Screen, premultiplied(CB, 0.5), premultiplied(CS, 0.5));
+        assert_close4(c, [0.5 * (0.5 * 0.25 + 0.5 * 0.85) + 0.25 * 0.8,
+                          0.5 * (0.5 * 0.5 + 0.5 * 0.7) + 0.25 * 0.4,
+                          0.5 * (0.5 * 0.75 + 0.5 * 0.8) + 0.25 * 0.2,
+                          0.75]);
+    }
+}
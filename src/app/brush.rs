@@ -1,15 +1,56 @@
+use std::rc::Rc;
+
 use app::color::Color;
+use app::tip;
+use app::tip::{Tip, Calligraphic, Stamp};
+use app::dynamics::Dynamics;
+
+pub const MIN_SIZE: i64 = 0;
+pub const MAX_SIZE: i64 = 10;
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum BrushTip {
-    Contour,
+    Contour, // round and hard, what the contour of a shape is drawn with
+    RoundHard,
+    RoundSoft,
+    Square,
+    Calligraphic(Calligraphic),
+    Stamp(Rc<Stamp>),
+}
+
+impl BrushTip {
+    pub fn shape(&self) -> &Tip {
+        match self {
+            &BrushTip::Contour | &BrushTip::RoundHard => &tip::RoundHard,
+            &BrushTip::RoundSoft => &tip::RoundSoft,
+            &BrushTip::Square => &tip::Square,
+            &BrushTip::Calligraphic(ref c) => c,
+            &BrushTip::Stamp(ref s) => &**s,
+        }
+    }
+}
+
+// How a dab combines with the layer it is drawn on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BrushBlend {
+    Normal,
+    Multiply,
+    Screen,
+    Behind,    // paints only under what is already there
+    LockAlpha, // recolors without changing coverage
+    Erase,     // lowers alpha
 }
 
 #[derive(Clone)]
 pub struct Brush {
     pub tip: BrushTip,
     pub size: f64,
-    pub color: Color<f64>,
+    pub color: Color<f64>, // alpha of a single dab
+    pub opacity: f64, // the most a single stroke can cover, however often its dabs overlap
+    pub spacing: f64, // distance between dabs, relative to the diameter
+    pub dynamics: Dynamics,
+    pub blend: BrushBlend,
+    pub eraser: bool, // erase with this brush's shape instead of painting
 }
 
 impl Brush {
@@ -18,6 +59,19 @@ impl Brush {
             tip: BrushTip::Contour,
             size: 2.0,
             color: Color::new(0.0, 0.0, 0.0, 1.0),
+            opacity: 1.0,
+            spacing: 0.1,
+            dynamics: Dynamics::new(),
+            blend: BrushBlend::Normal,
+            eraser: false,
+        }
+    }
+
+    pub fn dab_blend(&self) -> BrushBlend {
+        if self.eraser {
+            BrushBlend::Erase
+        } else {
+            self.blend
         }
     }
 
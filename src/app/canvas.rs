@@ -1,28 +1,43 @@
-extern crate ui;
-extern crate time;
-
 use std::path::Path;
 use std::fs::File;
 use std::error::Error;
 use std::io::*;
-use std::process::Command;
+use std::mem;
+use std::rc::Rc;
 
 use message::Message;
-use reactive;
-use reactive::widget::{HandlerType, Model, AreaDrawParams, AreaMouseEvent, AreaHandler, AreaCallbacks};
+use reactive::property::Property;
 
 use app::stroke::{Stroke, StrokePoint};
 use app::vector::{Vec2d, Vec3d};
 use app::config::{Config};
-use app::brush::{Brush};
-use app::color::{Color, ColorRefMut};
+use app::brush::{Brush, BrushTip, BrushBlend};
+use app::brush;
+use app::color::{Color, ColorRefMut, Channel};
+use app::stamp::Stamper;
+use app::tip::{Tip, Stamp};
+use app::curve::BezierPath;
+use app::smoothing::{Stabilizer, StabilizerKind, Smoothing};
+use app::blend;
+use app::dynamics::{Dab, Sample, Dynamic, Input, Curve};
+use app::history::{History, TileSnapshot};
+use app::history;
+use app::document;
+use app::image_io;
+use app::scoring;
+
+pub const DEFAULT_TARGET_IMAGE: &'static str = "in/input.png";
+pub const DEFAULT_DOCUMENT: &'static str = "out/sketch.skb";
+pub const LAYER_ROWS: usize = 8; // in the layers panel
+const CLOSE_TOLERANCE: f64 = 1.5; // px, for fitting the closed stroke
+const CLOSE_SPACING: f64 = 2.0;   // px between its resampled points
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Rect<T: PartialOrd> {
-    lt_x: T,
-    lt_y: T,
-    rb_x: T,
-    rb_y: T,
+    pub lt_x: T,
+    pub lt_y: T,
+    pub rb_x: T,
+    pub rb_y: T,
 }
 
 impl<T: PartialOrd> Rect<T> {
@@ -61,38 +76,145 @@ pub fn saturate<T: PartialOrd>(v: T, min: T, max: T) -> T {
     }
 }
 
-pub struct CanvasImage {
-    data: Vec<u8>,
+// Images hold RGBA with the color premultiplied by alpha, so that
+// compositing is a plain weighted sum. They are converted from and to
+// straight alpha only where they enter or leave the app (files and the
+// screen).
+pub fn premultiply(data: &mut [u8]) {
+    for p in data.chunks_mut(4) {
+        let a = p[3] as u32;
+        for k in 0..3 {
+            p[k] = ((p[k] as u32 * a + 127) / 255) as u8;
+        }
+    }
+}
+
+// Premultiplied channels to straight RGBA8.
+fn straight_bytes<T: Channel>(data: &[T]) -> Vec<u8> {
+    let mut straight = Vec::with_capacity(data.len());
+    for p in data.chunks(4) {
+        let a = p[3].to_f64();
+        for k in 0..3 {
+            straight.push(if a > 0.0 { u8::from_f64(p[k].to_f64() / a) } else { 0 });
+        }
+        straight.push(u8::from_f64(a));
+    }
+    straight
+}
+
+fn convert_data<T: Channel, U: Channel>(data: &[T]) -> Vec<U> {
+    data.iter().map(|&v| U::from_f64(v.to_f64())).collect()
+}
+
+// Layers keep 16 bits per channel, so that faint dabs add up instead of
+// rounding away. Files and the screen stay 8 bit (the default).
+pub type LayerChannel = u16;
+pub type LayerImage = CanvasImage<LayerChannel>;
+
+pub struct CanvasImage<T = u8> {
+    data: Vec<T>, // premultiplied
     width: u32,
     height: u32,
-    color_depth: u32, // in byte
+    stroke_alpha: Option<Vec<f32>>, // how much the stroke being drawn covers each pixel
+    stamper: Stamper, // dab spacing of the stroke being drawn
 }
 
-impl CanvasImage {
-    pub fn new(w: u32, h: u32, init_value: u8) -> CanvasImage {
-        let color_depth = 1; // 1 byte = 0..255 per color
+impl<T: Channel> CanvasImage<T> {
+    // `init_value` is an 8 bit level for every channel.
+    pub fn new(w: u32, h: u32, init_value: u8) -> CanvasImage<T> {
+        CanvasImage::from_data(w, h, vec![T::from_f64(init_value.to_f64()); (w * h * 4) as usize])
+    }
+
+    pub fn from_data(w: u32, h: u32, data: Vec<T>) -> CanvasImage<T> {
+        assert_eq!(data.len(), (w * h * 4) as usize);
         CanvasImage {
-            data: vec![init_value; (w * h * color_depth * 4) as usize],
+            data: data,
             width: w,
             height: h,
-            color_depth: color_depth,
+            stroke_alpha: None,
+            stamper: Stamper::new(),
         }
     }
 
+    // From straight RGBA8, as decoded from a file.
+    pub fn from_straight(w: u32, h: u32, data: Vec<u8>) -> CanvasImage<T> {
+        let mut data = data;
+        premultiply(&mut data);
+        CanvasImage::from_data(w, h, convert_data(&data))
+    }
+
+    // The same image with another channel type.
+    pub fn convert<U: Channel>(&self) -> CanvasImage<U> {
+        CanvasImage::from_data(self.width, self.height, convert_data(&self.data))
+    }
+
+    pub fn data(&self) -> &Vec<T> {
+        &self.data
+    }
+
+    // Straight RGBA8, for writing to a file.
+    pub fn straight_data(&self) -> Vec<u8> {
+        straight_bytes(&self.data)
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [T; 4] {
+        let i = (y * self.width as usize + x) * 4;
+        [self.data[i], self.data[i + 1], self.data[i + 2], self.data[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, p: [T; 4]) {
+        let i = (y * self.width as usize + x) * 4;
+        self.data[i..i + 4].copy_from_slice(&p);
+    }
+
+    // Straight color of the pixel.
     pub fn at(&self, x: usize, y: usize) -> Color<u8> {
-        Color {
-            r: self.data[(y * self.width as usize + x) * 4 + 0],
-            g: self.data[(y * self.width as usize + x) * 4 + 1],
-            b: self.data[(y * self.width as usize + x) * 4 + 2],
-            a: self.data[(y * self.width as usize + x) * 4 + 3],
-        }
+        let p = straight_bytes(&self.pixel(x, y));
+        Color { r: p[0], g: p[1], b: p[2], a: p[3] }
     }
 
+    // Sets the pixel to a straight color.
     pub fn set_color(&mut self, x: usize, y: usize, color: Color<u8>) {
-        self.data[(y * self.width as usize + x) * 4 + 0] = color.r;
-        self.data[(y * self.width as usize + x) * 4 + 1] = color.g;
-        self.data[(y * self.width as usize + x) * 4 + 2] = color.b;
-        self.data[(y * self.width as usize + x) * 4 + 3] = color.a;
+        let mut p = [color.r, color.g, color.b, color.a];
+        premultiply(&mut p);
+        self.set_pixel(x, y, [T::from_f64(p[0].to_f64()), T::from_f64(p[1].to_f64()),
+                              T::from_f64(p[2].to_f64()), T::from_f64(p[3].to_f64())]);
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Copies the pixels inside `rect` (which must lie within the image) row by row.
+    pub fn region(&self, rect: &Rect<i32>) -> Vec<T> {
+        let mut buff = Vec::with_capacity(((rect.rb_x - rect.lt_x) * (rect.rb_y - rect.lt_y) * 4) as usize);
+        for y in rect.lt_y..rect.rb_y {
+            let i = ((y * self.width as i32 + rect.lt_x) * 4) as usize;
+            let j = ((y * self.width as i32 + rect.rb_x) * 4) as usize;
+            buff.extend_from_slice(&self.data[i..j]);
+        }
+        buff
+    }
+
+    pub fn set_region(&mut self, rect: &Rect<i32>, data: &[T]) {
+        let row = ((rect.rb_x - rect.lt_x) * 4) as usize;
+        for (k, y) in (rect.lt_y..rect.rb_y).enumerate() {
+            let i = ((y * self.width as i32 + rect.lt_x) * 4) as usize;
+            self.data[i..i + row].copy_from_slice(&data[k * row..(k + 1) * row]);
+        }
+    }
+
+    // Copy of the image on a w x h canvas, anchored at the top left corner.
+    // Pixels outside the original image are transparent.
+    pub fn resize_canvas(&self, w: u32, h: u32) -> CanvasImage<T> {
+        let mut resized = CanvasImage::new(w, h, 0);
+        let rect = Rect::new(0, 0, self.width.min(w) as i32, self.height.min(h) as i32);
+        resized.set_region(&rect, &self.region(&rect));
+        resized
     }
 //    pub fn draw_stroke_dots(&mut self) {
 //        for s in &self.strokes {
@@ -106,32 +228,96 @@ impl CanvasImage {
 //            }
 //        }
 //    }
-    pub fn fill_circle(&mut self, c: Vec2d, brush: &Brush) -> Option<Rect<i32>> {
-        let r_int = brush.size as i32;
-        let cx_int = c.x as i32;
-        let cy_int = c.y as i32;
-        let lt_x = saturate(cx_int - r_int, 0, self.width as i32);
-        let lt_y = saturate(cy_int - r_int, 0, self.height as i32);
-        let rb_x = saturate(cx_int + r_int, 0, self.width as i32);
-        let rb_y = saturate(cy_int + r_int, 0, self.height as i32);
+    // Dabs drawn between begin_stroke and end_stroke belong to one stroke:
+    // they build up on each other only until the stroke covers a pixel with
+    // the brush opacity.
+    pub fn begin_stroke(&mut self) {
+        self.stroke_alpha = Some(vec![0.0; (self.width * self.height) as usize]);
+    }
+
+    pub fn end_stroke(&mut self) {
+        self.stroke_alpha = None;
+    }
+
+    // Draws the straight color `col` with alpha `a` onto pixel `i`.
+    fn blend_pixel(&mut self, i: usize, col: &Color<f64>, a: f64, blend: BrushBlend) {
+        let a_back = self.data[i + 3].to_f64();
+        let a_out = match blend {
+            BrushBlend::Erase => a_back * (1.0 - a),
+            BrushBlend::LockAlpha => a_back,
+            _ => a + a_back * (1.0 - a),
+        };
+        let front = [col.r, col.g, col.b];
+        for k in 0..3 {
+            // premultiplied, so the background needs no dividing out
+            let back = self.data[i + k].to_f64();
+            let v = match blend {
+                BrushBlend::Erase => back * (1.0 - a),
+                BrushBlend::Behind => back + front[k] * a * (1.0 - a_back),
+                BrushBlend::LockAlpha => back + (front[k] * a_back - back) * a,
+                // where there is paint below, multiply and screen mix with it
+                BrushBlend::Multiply => a * ((1.0 - a_back) * front[k] + front[k] * back) + back * (1.0 - a),
+                BrushBlend::Screen => a * (front[k] + back * (1.0 - front[k])) + back * (1.0 - a),
+                _ => front[k] * a + back * (1.0 - a),
+            };
+            self.data[i + k] = T::from_f64(saturate(v, 0.0, a_out));
+        }
+        self.data[i + 3] = T::from_f64(saturate(a_out, 0.0, 1.0));
+    }
+
+    // One dab of `tip` with radius `dab.size` centered at `c` (sub-pixel).
+    // Dabs thinner than a pixel are drawn at half a pixel radius with the
+    // coverage scaled down by area. The dab is composited with source-over,
+    // the tip's coverage times the dab's flow.
+    pub fn fill_dab(&mut self, c: Vec2d, dab: &Dab, tip: &Tip, col: &Color<f64>) -> Option<Rect<i32>> {
+        if dab.size <= 0.0 {
+            return None;
+        }
+        let r = dab.size.max(0.5);
+        let scale = (dab.size / r).powi(2);
+        let reach = tip.reach(r) + 0.5;
+        let lt_x = saturate((c.x - reach).floor() as i32, 0, self.width as i32);
+        let lt_y = saturate((c.y - reach).floor() as i32, 0, self.height as i32);
+        let rb_x = saturate((c.x + reach).ceil() as i32, 0, self.width as i32);
+        let rb_y = saturate((c.y + reach).ceil() as i32, 0, self.height as i32);
+        let flow = saturate(dab.flow, 0.0, 1.0);
+        let cap = saturate(dab.opacity, 0.0, 1.0);
         for y_int in lt_y..rb_y {
             for x_int in lt_x..rb_x {
-                if (x_int - cx_int).pow(2) + (y_int - cy_int).pow(2) < r_int.pow(2) {
-                    let col = brush.get_color();
-                    self.data[((y_int * (self.width as i32) + x_int) * 4 + 0) as usize] = (col.r * 255.0) as u8;
-                    self.data[((y_int * (self.width as i32) + x_int) * 4 + 1) as usize] = (col.g * 255.0) as u8;
-                    self.data[((y_int * (self.width as i32) + x_int) * 4 + 2) as usize] = (col.b * 255.0) as u8;
-                    self.data[((y_int * (self.width as i32) + x_int) * 4 + 3) as usize] = 255;
+                let coverage = tip.coverage(x_int as f64 + 0.5 - c.x, y_int as f64 + 0.5 - c.y, r) * scale;
+                let alpha = coverage * flow;
+                if alpha <= 0.0 {
+                    continue;
                 }
+                let k = (y_int * (self.width as i32) + x_int) as usize;
+                let a = match self.stroke_alpha {
+                    // The pixel already holds the stroke at alpha a0. Raising
+                    // it to a1 is the same as one more source-over of the
+                    // brush color with alpha (a1 - a0) / (1 - a0).
+                    Some(ref mut stroke_alpha) => {
+                        let a0 = stroke_alpha[k] as f64;
+                        let a1 = (a0 + alpha * (1.0 - a0)).min(cap);
+                        if a1 <= a0 {
+                            continue;
+                        }
+                        stroke_alpha[k] = a1 as f32;
+                        (a1 - a0) / (1.0 - a0)
+                    },
+                    None => alpha.min(cap),
+                };
+                self.blend_pixel(k * 4, col, a, dab.blend);
             }
         }
         Some(Rect::new(lt_x, lt_y, rb_x, rb_y))
     }
-    pub fn draw_line_with_circle(&mut self, p0: Vec2d, p1: Vec2d, brush: &Brush) -> Option<Rect<i32>> {
-        let d = p1 - p0;
+    // Dabs at `ts` along the segment p0-p1, each shaped by the brush dynamics.
+    fn stamp(&mut self, ts: Vec<f64>, p0: &StrokePoint, p1: &StrokePoint, brush: &Brush) -> Option<Rect<i32>> {
+        let a = Vec2d::new(p0.x, p0.y);
+        let d = Vec2d::new(p1.x, p1.y) - a;
         let mut rect: Option<Rect<i32>> = None;
-        for t in 0..(d.len() as usize) + 1 {
-            let r1 = self.fill_circle(p0 + d.normalize().smul(t as f64), brush);
+        for t in ts {
+            let dab = Dab::new(brush, &Sample::between(p0, p1, t));
+            let r1 = self.fill_dab(a + d.smul(t), &dab, brush.tip.shape(), brush.get_color());
             if let Some(r1) = r1 {
                 if let Some(mut r0) = rect {
                     r0.merge(r1);
@@ -146,10 +332,13 @@ impl CanvasImage {
     pub fn draw_stroke_sweep_circle(&mut self, strokes: &Vec<Stroke>, brush: &Brush) -> Option<Rect<i32>> {
         let mut rect: Option<Rect<i32>> = None;
         for i in 0..strokes.len() {
-            for j in 1..strokes[i].points.len() {
-                let p0 = Vec2d::new(strokes[i].points[j-1].x as f64, strokes[i].points[j-1].y as f64);
-                let p1 = Vec2d::new(strokes[i].points[j].x as f64, strokes[i].points[j].y as f64);
-                let r1 = self.draw_line_with_circle(p0, p1, brush);
+            let points = &strokes[i].points;
+            let mut stamper = Stamper::new();
+            for j in 0..points.len() {
+                let p0 = if j > 0 { &points[j-1] } else { &points[0] };
+                let p1 = &points[j];
+                let ts = stamper.advance(Vec2d::new(p0.x, p0.y), Vec2d::new(p1.x, p1.y), brush);
+                let r1 = self.stamp(ts, p0, p1, brush);
                 if let Some(r1) = r1 {
                     if let Some(mut r0) = rect {
                         r0.merge(r1);
@@ -165,48 +354,237 @@ impl CanvasImage {
     pub fn draw_stroke(&mut self, strokes: &Vec<Stroke>, brush: &Brush) -> Option<Rect<i32>> {
         self.draw_stroke_sweep_circle(strokes, brush)
     }
+    // Draws the newest point of the last stroke. The spacing carries over
+    // from the previous call; a stroke's first point starts it over.
     pub fn draw_stroke_incremental(&mut self, strokes: &Vec<Stroke>, brush: &Brush) -> Option<Rect<i32>> {
         if strokes.len() > 0 {
-            let last = strokes.len() - 1;
-            let len = strokes[last].points.len();
+            let points = &strokes[strokes.len() - 1].points;
+            let len = points.len();
             if len == 1 {
-                let x = strokes[last].points[len-1].x;
-                let y = strokes[last].points[len-1].y;
-                return self.fill_circle(Vec2d::new(x, y), brush);
+                let p = &points[0];
+                self.stamper = Stamper::new();
+                let ts = self.stamper.advance(Vec2d::new(p.x, p.y), Vec2d::new(p.x, p.y), brush);
+                return self.stamp(ts, p, p, brush);
             } else if len > 1 {
-                let x0 = strokes[last].points[len-2].x;
-                let y0 = strokes[last].points[len-2].y;
-                let x1 = strokes[last].points[len-1].x;
-                let y1 = strokes[last].points[len-1].y;
-                return self.draw_line_with_circle(Vec2d::new(x0, y0), Vec2d::new(x1, y1), brush);
+                let (p0, p1) = (&points[len-2], &points[len-1]);
+                let ts = self.stamper.advance(Vec2d::new(p0.x, p0.y), Vec2d::new(p1.x, p1.y), brush);
+                return self.stamp(ts, p0, p1, brush);
             }
         }
         return None;
     }
 }
 
+// How a layer mixes with what is below it, see `blend`.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum BlendMode {
     Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Add,
+    Subtract,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
 }
 
 pub struct Layer {
+    id: u32, // stays the same when layers are added, removed or moved
+    name: String,
     visible: bool,
     blend_mode: BlendMode,
-    image: CanvasImage,
+    opacity: f64,
+    mask: Option<LayerImage>, // gray, white shows the layer and black hides it
+    clip: bool, // only shows where the nearest unclipped layer below has paint
+    normals: Option<CanvasImage<f32>>, // signed field a normal map layer was made from
+    image: LayerImage,
     strokes: Vec<Stroke>,
+    pending_tiles: Option<TileSnapshot>, // tiles touched by the stroke being drawn
+}
+
+// Area the incremental drawing of the segment p0-p1 can touch.
+fn segment_bounds(p0: &StrokePoint, p1: &StrokePoint, brush: &Brush) -> Rect<i32> {
+    let r = brush.tip.shape().reach(brush.size.max(0.5)) + 1.0;
+    Rect::new((p0.x.min(p1.x) - r).floor() as i32, (p0.y.min(p1.y) - r).floor() as i32,
+              (p0.x.max(p1.x) + r).ceil() as i32, (p0.y.max(p1.y) + r).ceil() as i32)
 }
 
 impl Layer {
-    pub fn new(width: u32, height: u32, init_value: u8) -> Layer {
+    pub fn new(id: u32, name: &str, width: u32, height: u32, init_value: u8) -> Layer {
         Layer {
+            id: id,
+            name: name.to_string(),
             visible: true,
             blend_mode: BlendMode::Normal,
+            opacity: 1.0,
+            mask: None,
+            clip: false,
+            normals: None,
             image: CanvasImage::new(width, height, init_value),
             strokes: vec![],
+            pending_tiles: None,
+        }
+    }
+
+    pub fn from_parts(id: u32, name: String, visible: bool, blend_mode: BlendMode,
+                      image: LayerImage, strokes: Vec<Stroke>) -> Layer {
+        Layer {
+            id: id,
+            name: name,
+            visible: visible,
+            blend_mode: blend_mode,
+            opacity: 1.0,
+            mask: None,
+            clip: false,
+            normals: None,
+            image: image,
+            strokes: strokes,
+            pending_tiles: None,
+        }
+    }
+
+    pub fn from_image(id: u32, name: &str, image: LayerImage) -> Layer {
+        Layer::from_parts(id, name.to_string(), true, BlendMode::Normal, image, vec![])
+    }
+
+    // A copy of the pixels, strokes and settings under a new id.
+    pub fn duplicate(&self, id: u32, name: &str) -> Layer {
+        let image = CanvasImage::from_data(self.image.width, self.image.height, self.image.data.clone());
+        let mut layer = Layer::from_parts(id, name.to_string(), self.visible, self.blend_mode, image, self.strokes.clone());
+        layer.opacity = self.opacity;
+        layer.clip = self.clip;
+        layer.mask = self.mask.as_ref().map(|m| CanvasImage::from_data(m.width, m.height, m.data.clone()));
+        layer.normals = self.normals.as_ref().map(|n| CanvasImage::from_data(n.width, n.height, n.data.clone()));
+        layer
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    pub fn blend_mode(&self) -> &BlendMode {
+        &self.blend_mode
+    }
+
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
+    pub fn opacity(&self) -> f64 {
+        self.opacity
+    }
+
+    pub fn set_opacity(&mut self, opacity: f64) {
+        self.opacity = saturate(opacity, 0.0, 1.0);
+    }
+
+    pub fn clip(&self) -> bool {
+        self.clip
+    }
+
+    pub fn set_clip(&mut self, clip: bool) {
+        self.clip = clip;
+    }
+
+    pub fn mask(&self) -> Option<&LayerImage> {
+        self.mask.as_ref()
+    }
+
+    pub fn mask_mut(&mut self) -> &mut Option<LayerImage> {
+        &mut self.mask
+    }
+
+    pub fn normals(&self) -> Option<&CanvasImage<f32>> {
+        self.normals.as_ref()
+    }
+
+    pub fn normals_mut(&mut self) -> &mut Option<CanvasImage<f32>> {
+        &mut self.normals
+    }
+
+    // How much opacity and mask let through of the pixel starting at byte `j`.
+    fn strength(&self, j: usize) -> f64 {
+        let mask = match self.mask {
+            Some(ref m) => m.data[j].to_f64(),
+            None => 1.0,
+        };
+        self.opacity * mask
+    }
+
+    // How much of the pixel the layer covers: its alpha scaled by opacity and mask.
+    fn coverage(&self, j: usize) -> f64 {
+        self.image.data[j+3].to_f64() * self.strength(j)
+    }
+
+    pub fn strokes(&self) -> &Vec<Stroke> {
+        &self.strokes
+    }
+
+    pub fn strokes_mut(&mut self) -> &mut Vec<Stroke> {
+        &mut self.strokes
+    }
+
+    pub fn image(&self) -> &LayerImage {
+        &self.image
+    }
+
+    pub fn image_mut(&mut self) -> &mut LayerImage {
+        &mut self.image
+    }
+
+    pub fn is_drawing(&self) -> bool {
+        match self.strokes.last() {
+            Some(s) => !s.finished,
+            None => false,
         }
     }
+
+    // Hands out the last stroke and the tiles it overwrote once the stroke is finished.
+    pub fn take_finished_edit(&mut self) -> Option<(Stroke, TileSnapshot)> {
+        match self.strokes.last() {
+            Some(s) if s.finished => self.pending_tiles.take().map(|tiles| (s.clone(), tiles)),
+            _ => None,
+        }
+    }
+
     pub fn mouse_event(&mut self, brush: &Brush, e: StrokePoint) -> Option<Rect<i32>> {
         if e.dragging {
+            let bounds = match self.strokes.last() {
+                Some(s) if !s.finished && s.len() > 0 => segment_bounds(&s[s.len() - 1], &e, brush),
+                _ => segment_bounds(&e, &e, brush),
+            };
+            if self.pending_tiles.is_none() {
+                self.pending_tiles = Some(TileSnapshot::new());
+            }
+            if let Some(ref mut tiles) = self.pending_tiles {
+                tiles.capture(&self.image, &bounds);
+            }
             let mut new_stroke = match self.strokes.pop() {
                 Some(s) => if !s.finished { s } else {
                     self.strokes.push(s);
@@ -214,51 +592,197 @@ impl Layer {
                 },
                 None => Stroke::new(10, brush.clone()),
             };
+            if new_stroke.len() == 0 {
+                self.image.begin_stroke();
+            }
             new_stroke.points.push(e);
             self.strokes.push(new_stroke);
             self.image.draw_stroke_incremental(&self.strokes, brush)
         } else if self.strokes.len() > 0 {
             self.strokes.last_mut().unwrap().finished = true;
+            self.image.end_stroke();
             None
         } else {
             None
         }
     }
 
-    pub fn composite(&self, data: &mut Vec<u8>, rect: &Rect<i32>) {
-        match self.blend_mode {
-            BlendMode::Normal => {
-                for y in saturate(rect.lt_y as usize, 0, self.image.height as usize)..
-                         saturate(rect.rb_y as usize, 0, self.image.height as usize) {
-                    for x in saturate(rect.lt_x as usize, 0, self.image.width as usize)..
-                             saturate(rect.rb_x as usize, 0, self.image.width as usize) {
-                        let j = (y * self.image.width as usize + x) * 4;
-                        let a_back = data[j+3] as f64 / 255.0;
-                        let a_front = self.image.data[j+3] as f64 / 255.0;
-                        data[j+0] = (self.image.data[j+0] as f64 * a_front + data[j+0] as f64 * a_back * (1.0 - a_front)) as u8;
-                        data[j+1] = (self.image.data[j+1] as f64 * a_front + data[j+1] as f64 * a_back * (1.0 - a_front)) as u8;
-                        data[j+2] = (self.image.data[j+2] as f64 * a_front + data[j+2] as f64 * a_back * (1.0 - a_front)) as u8;
-                        data[j+3] = ((a_front + a_back * (1.0 - a_front)) * 255.0) as u8;
-                    }
+    // Replaces the points of the stroke that was just finished with smoothed
+    // ones and draws it again. Returns the area that changed.
+    pub fn smooth_finished_stroke(&mut self, smoothing: Smoothing) -> Option<Rect<i32>> {
+        let points = match self.strokes.last() {
+            Some(s) if s.finished && self.pending_tiles.is_some() && smoothing != Smoothing::None => smoothing.apply(&s.points),
+            _ => return None,
+        };
+        let mut stroke = self.strokes.pop().unwrap();
+        stroke.points = points;
+        // back to the image from before the stroke, remembering it for undo
+        let mut rect = self.pending_tiles.take().unwrap().swap(&mut self.image);
+        let mut tiles = TileSnapshot::new();
+        if let Some(ref r) = rect {
+            tiles.capture(&self.image, r);
+        }
+        for (i, p) in stroke.points.iter().enumerate() {
+            let bounds = segment_bounds(&stroke.points[i.max(1) - 1], p, &stroke.brush);
+            tiles.capture(&self.image, &bounds);
+        }
+        self.image.begin_stroke();
+        let r1 = self.image.draw_stroke(&vec![stroke.clone()], &stroke.brush);
+        self.image.end_stroke();
+        if let Some(r1) = r1 {
+            if let Some(ref mut r0) = rect {
+                r0.merge(r1);
+            } else {
+                rect = Some(r1);
+            }
+        }
+        self.strokes.push(stroke);
+        self.pending_tiles = Some(tiles);
+        rect
+    }
+
+    // Draws the layer onto `data`. A clipped layer passes the layer it is
+    // clipped to as `base`.
+    pub fn composite(&self, data: &mut Vec<LayerChannel>, rect: &Rect<i32>, base: Option<&Layer>) {
+        for y in saturate(rect.lt_y as usize, 0, self.image.height as usize)..
+                 saturate(rect.rb_y as usize, 0, self.image.height as usize) {
+            for x in saturate(rect.lt_x as usize, 0, self.image.width as usize)..
+                     saturate(rect.rb_x as usize, 0, self.image.width as usize) {
+                let j = (y * self.image.width as usize + x) * 4;
+                let mut strength = self.strength(j);
+                if let Some(base) = base {
+                    strength *= base.coverage(j);
+                }
+                if strength == 0.0 || self.image.data[j+3] == 0 {
+                    continue;
+                }
+                let pixel = |d: &[LayerChannel], s: f64| {
+                    [d[j].to_f64() * s, d[j+1].to_f64() * s, d[j+2].to_f64() * s, d[j+3].to_f64() * s]
+                };
+                let c = blend::composite(self.blend_mode, pixel(data, 1.0), pixel(&self.image.data, strength));
+                for k in 0..4 {
+                    data[j+k] = LayerChannel::from_f64(c[k]);
+                }
+            }
+        }
+    }
+
+    // Returns what was removed so that the clear can be undone.
+    pub fn clear(&mut self) -> (Vec<Stroke>, TileSnapshot) {
+        let blank = CanvasImage::new(self.image.width, self.image.height, 0);
+        let tiles = TileSnapshot::diff(&self.image, &blank);
+        self.image = blank;
+        self.pending_tiles = None;
+        (mem::replace(&mut self.strokes, vec![]), tiles)
+    }
+
+    // Draws `upper` onto this layer and takes over its strokes. A hidden
+    // layer adds nothing but its strokes. This layer's opacity and mask go
+    // into its pixels first, so they don't fade what comes from above.
+    // Returns what was replaced so that the merge can be undone: strokes,
+    // pixels, opacity and mask.
+    pub fn merge(&mut self, upper: &Layer) -> (Vec<Stroke>, TileSnapshot, f64, Option<LayerImage>) {
+        let mut baked = self.image.data.clone();
+        for i in 0..baked.len() / 4 {
+            let j = i * 4;
+            let strength = self.strength(j);
+            if strength < 1.0 {
+                for k in 0..4 {
+                    baked[j+k] = LayerChannel::from_f64(baked[j+k].to_f64() * strength);
                 }
             }
         }
+        let baked = CanvasImage::from_data(self.image.width, self.image.height, baked);
+        let original = mem::replace(&mut self.image, baked);
+        let opacity = mem::replace(&mut self.opacity, 1.0);
+        let mask = self.mask.take();
+        let mut data = self.image.data.clone();
+        if upper.visible {
+            let rect = Rect::new(0, 0, self.image.width as i32, self.image.height as i32);
+            // when both are clipped, the result still is
+            let base = if upper.clip && !self.clip { Some(&*self) } else { None };
+            upper.composite(&mut data, &rect, base);
+        }
+        let merged = CanvasImage::from_data(self.image.width, self.image.height, data);
+        let tiles = TileSnapshot::diff(&original, &merged);
+        self.image = merged;
+        let mut strokes = self.strokes.clone();
+        strokes.extend(upper.strokes.iter().cloned());
+        (mem::replace(&mut self.strokes, strokes), tiles, opacity, mask)
+    }
+}
+
+// Composites the visible layers, bottom first. Clipped layers are clipped
+// to the nearest unclipped layer below and are hidden along with it.
+pub fn composite_layers(layers: &Vec<Layer>, data: &mut Vec<LayerChannel>, rect: &Rect<i32>) {
+    let mut base: Option<&Layer> = None;
+    for l in layers {
+        if !l.clip || base.is_none() {
+            base = Some(l);
+            if l.visible {
+                l.composite(data, rect, None);
+            }
+        } else if l.visible && base.map_or(false, |b| b.visible) {
+            l.composite(data, rect, base);
+        }
     }
+}
+
+// Position of the layer with the given id, counted from the bottom.
+pub fn layer_index(layers: &Vec<Layer>, id: u32) -> Option<usize> {
+    layers.iter().position(|l| l.id == id)
+}
+
+fn tool_name(brush: &Brush) -> &'static str {
+    if brush.eraser { "eraser" } else { "pen" }
+}
 
-    pub fn clear(&mut self) {
-        self.strokes = vec![];
-        self.image.data = vec![0; (self.image.width * self.image.height * self.image.color_depth * 4) as usize];
+// One row of the layers panel. Rows run from the top of the stack down;
+// those below the bottom layer are blank, with id 0.
+#[derive(Clone)]
+pub struct LayerRow {
+    pub id: Property<i64>,
+    pub label: Property<String>, // name, marked when active
+    pub visible: Property<bool>,
+}
+
+impl LayerRow {
+    fn new() -> LayerRow {
+        LayerRow {
+            id: Property::new(0),
+            label: Property::new(String::new()),
+            visible: Property::new(false),
+        }
     }
 }
 
-pub struct CanvasModel {
-    layers: Vec<Layer>,
+// The drawing itself: layers, brush, history and the composited image.
+// Nothing in here touches libui, so it also runs headless.
+pub struct Canvas {
+    layers: Vec<Layer>, // bottom to top
     active_layer: usize,
-    image_cache: Vec<u8>,
+    next_layer_id: u32,
+    image_cache: Vec<LayerChannel>,
 //    config: Rc<Config>,
     width: f64,
     height: f64,
     current_brush: Brush, // TODO move it to config
+    history: History,
+    overlay: Option<Layer>, // result of the last matching, drawn on top and never edited
+    score: Option<f64>, // of the last matching, until someone takes it
+    show_overlay: Property<bool>,
+    brush_size: Property<i64>,
+    tool: Property<String>, // "pen" or "eraser"
+    layer_rows: Vec<LayerRow>,
+    layer_name: Property<String>, // of the active layer
+    layer_blend: Property<i64>, // index into blend::MODES
+    layer_opacity: Property<i64>, // percent
+    layer_clip: Property<bool>,
+    layer_masked: Property<bool>,
+    target_image: String,
+    document_path: String,
+    stabilizer: Stabilizer,
+    smoothing: Smoothing,
 }
 
 fn connect_closed_points(mut strokes: Vec<Stroke>) -> Vec<Stroke> {
@@ -362,7 +886,36 @@ fn diffuse_normal(normals: &Vec<(Vec2d, Vec3d)>, p: Vec2d) -> Vec3d {
     diffused_n.normalize()
 }
 
-fn get_normal_field(w: u32, h: u32, stroke: &Stroke) -> CanvasImage {
+// The strokes of the layer joined into a closed one, sampled along the curve
+// fitted to it, and that curve; None when there are none.
+fn closed_stroke(layer: &Layer) -> Option<(Stroke, BezierPath)> {
+    if layer.strokes.is_empty() {
+        println!("no strokes to close on the layer");
+        return None;
+    }
+    // the fitted curve instead of the jittery samples
+    let joined = get_closed_stroke(&layer.strokes).swap_remove(0);
+    let path = joined.fit_curve(CLOSE_TOLERANCE);
+    Some((joined.resampled(&path, CLOSE_SPACING), path))
+}
+
+// White inside the closed stroke, black outside.
+fn get_inside_mask(w: u32, h: u32, stroke: &Stroke) -> LayerImage {
+    let mut mask = LayerImage::new(w, h, 255);
+    for y in 0..h as usize {
+        for x in 0..w as usize {
+            if !inside_curve(stroke, &Vec2d::new(x as f64, y as f64)) {
+                mask.set_color(x, y, Color::new(0, 0, 0, 255));
+            }
+        }
+    }
+    mask
+}
+
+// Unit normals (x, y, z) inside the closed stroke with 1 as the fourth
+// component, zero outside. The outline's normals come from `path`, the curve
+// the stroke was sampled along.
+fn get_normal_field(w: u32, h: u32, stroke: &Stroke, path: &BezierPath) -> CanvasImage<f32> {
     let mut field = CanvasImage::new(w, h, 0);
     let mut max_x = 0;
     let mut max_y = 0;
@@ -382,36 +935,26 @@ fn get_normal_field(w: u32, h: u32, stroke: &Stroke) -> CanvasImage {
     }
 
     let mut source_normals: Vec<(Vec2d, Vec3d)>= Vec::new();
-    let num_points = stroke.points.len();
+    let count = (path.length() / CLOSE_SPACING).ceil().max(1.0) as usize;
     let EPS = 10e-6;
-    for i in 0..num_points {
-        let p = Vec2d::new(stroke.points[i].x, stroke.points[i].y);
-        let prev = &stroke.points[(i-1 + num_points) % num_points];
-        let next = &stroke.points[(i+1 + num_points) % num_points];
-        let s = -(next.x - prev.x) / (next.y - prev.y);
-        if s == 0.0 {
-            source_normals.push((p, Vec3d::new(0.0, 1.0, 0.0)));
-        } else if s.is_infinite() {
-            source_normals.push((p, Vec3d::new(1.0, 0.0, 0.0)));
-        } else if s.is_nan() {
-            source_normals.push((p, Vec3d::new(0.0, 0.0, 0.0)));
-        } else {
-            let mut dir = Vec2d::new(1.0, s).normalize();
-            let v = Vec2d::new(stroke.points[i].x, stroke.points[i].y);
-            if inside_curve(stroke, &(v + dir.clone().smul(EPS))) {
-                dir = dir.smul(-1.0);
-            }
-            source_normals.push((p, Vec3d::from_vec2d(dir)));
+    for k in 0..count {
+        let t = path.t_at_length(path.length() * k as f64 / count as f64);
+        let p = path.point_at(t);
+        let tangent = path.tangent_at(t);
+        // zero where the curve stops, pointing out of the outline elsewhere
+        let mut dir = Vec2d::new(-tangent.y, tangent.x);
+        if inside_curve(stroke, &(p + dir.clone().smul(EPS))) {
+            dir = dir.smul(-1.0);
         }
+        source_normals.push((p, Vec3d::from_vec2d(dir)));
     }
 //    source_normals.push((Vec2d::new(max_x as f64, max_y as f64), Vec3d::new(0.0, 0.0, 1.0).smul(num_points as f64 / 3.0)));
     for y in 0..h as usize {
         for x in 0..w as usize {
             let v = Vec2d::new(x as f64, y as f64);
             if inside_curve(stroke, &v) {
-                field.set_color(x, y, diffuse_normal(&source_normals, v).to_color());
-            } else {
-                field.set_color(x, y, Color { r: 0, g: 0, b: 0, a: 0, });
+                let n = diffuse_normal(&source_normals, v).normalize();
+                field.set_pixel(x, y, [n.x as f32, n.y as f32, n.z as f32, 1.0]);
             }
         }
     }
@@ -419,125 +962,920 @@ fn get_normal_field(w: u32, h: u32, stroke: &Stroke) -> CanvasImage {
     field
 }
 
-impl Model<Message> for CanvasModel {
-    fn update(&mut self, message: &Message, widget_handler: &mut HandlerType) {
+// The usual normal map colors, each component mapped from -1..1 to 0..1.
+fn normal_map(field: &CanvasImage<f32>) -> LayerImage {
+    let mut image = LayerImage::new(field.width, field.height, 0);
+    for (p, n) in image.data.chunks_mut(4).zip(field.data.chunks(4)) {
+        if n[3] > 0.0 {
+            for k in 0..3 {
+                p[k] = LayerChannel::from_f64(n[k] as f64 * 0.5 + 0.5);
+            }
+            p[3] = LayerChannel::from_f64(1.0);
+        }
+    }
+    image
+}
+
+impl Canvas {
+    pub fn update(&mut self, message: &Message) {
+        match message {
+            // a slider drag or typing goes on only as long as nothing else happens
+            &Message::BrushSliderUpdate(_) | &Message::RenameLayer(_) | &Message::LayerOpacity(_) => (),
+            _ => self.history.end_burst(),
+        }
         match message {
             &Message::BrushSliderUpdate(size) => {
-                self.current_brush.size = size as f64;
+                if self.current_brush.size != size as f64 {
+                    self.history.push_burst(history::Command::ChangeBrush { brush: self.current_brush.clone() },
+                                            history::Burst::BrushSize);
+                    self.current_brush.size = size as f64;
+                }
+            },
+            &Message::BrushSizeStep(step) => {
+                let size = saturate(self.current_brush.size as i64 + step, brush::MIN_SIZE, brush::MAX_SIZE);
+                if self.current_brush.size != size as f64 {
+                    self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                    self.current_brush.size = size as f64;
+                }
+            },
+            &Message::BrushTipSelect(ref tip) => {
+                self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                self.current_brush.tip = tip.clone();
+            },
+            &Message::BrushTipStamp(ref path) => {
+                match image_io::open(Path::new(path)) {
+                    Ok(image) => {
+                        self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                        self.current_brush.tip = BrushTip::Stamp(Rc::new(Stamp::from_image(&image)));
+                    },
+                    Err(why) => println!("couldn't open {}: {}", path, why),
+                }
+            },
+            &Message::StabilizerSelect(kind) => {
+                self.stabilizer = Stabilizer::new(kind);
+            },
+            &Message::SmoothingSelect(smoothing) => {
+                self.smoothing = smoothing;
+            },
+            &Message::PressureSize(on) => {
+                self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                self.current_brush.dynamics.size = if on {
+                    Dynamic::new(Input::Pressure, Curve::linear(0.2))
+                } else {
+                    Dynamic::none()
+                };
+            },
+            &Message::PressureOpacity(on) => {
+                self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                self.current_brush.dynamics.opacity = if on {
+                    Dynamic::new(Input::Pressure, Curve::linear(0.1))
+                } else {
+                    Dynamic::none()
+                };
             },
             &Message::BrushToggleButton => {
-                // TODO end stroke if valid and begen new one
+                self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                self.current_brush.eraser = !self.current_brush.eraser;
             },
-            &Message::StrokeCloseButton => { // FIXME fixed layer assignment
-                let st = get_closed_stroke(&self.layers[1].strokes);
-
-                let normal_field = get_normal_field(self.width as u32, self.height as u32, &st[0]); // FIXME check size of st
-                self.layers[0].image = normal_field;
-                self.layers[0].image.draw_stroke(&st, &self.current_brush);
+            &Message::BrushBlendSelect(blend) => {
+                self.history.push(history::Command::ChangeBrush { brush: self.current_brush.clone() });
+                self.current_brush.blend = blend;
+                self.current_brush.eraser = false;
+            },
+            &Message::StrokeCloseButton => {
+                if let Some((outline, path)) = closed_stroke(&self.layers[self.active_layer]) {
+                    let normal_field = get_normal_field(self.width as u32, self.height as u32, &outline, &path);
+                    let mut normal_map = normal_map(&normal_field);
+                    normal_map.draw_stroke(&vec![outline], &self.current_brush);
+                    // right below the strokes it was made from
+                    let id = self.new_layer_id();
+                    let index = self.active_layer;
+                    let mut layer = Layer::from_image(id, "Normals", normal_map);
+                    // kept signed for shading, the map is only what shows
+                    layer.normals = Some(normal_field);
+                    self.insert_layer(index, layer);
+                }
+            },
+            &Message::LayerOpacity(opacity) => {
+                let layer = &mut self.layers[self.active_layer];
+                let opacity = saturate(opacity, 0.0, 1.0);
+                if layer.opacity != opacity {
+                    let old = mem::replace(&mut layer.opacity, opacity);
+                    self.history.push_burst(history::Command::LayerOpacity { layer: layer.id, opacity: old },
+                                            history::Burst::LayerOpacity(layer.id));
+                }
                 let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
                 self.update_cache(&rect);
             },
+            &Message::LayerClip(clip) => {
+                let layer = &mut self.layers[self.active_layer];
+                if layer.clip != clip {
+                    layer.clip = clip;
+                    self.history.push(history::Command::LayerClip { layer: layer.id });
+                }
+                let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                self.update_cache(&rect);
+            },
+            &Message::AddLayerMask => {
+                if self.layers[self.active_layer].mask.is_none() {
+                    let mask = LayerImage::new(self.width as u32, self.height as u32, 255);
+                    self.replace_mask(Some(mask));
+                }
+            },
+            &Message::MaskFromClosedStroke => {
+                // the outline usually is on the layer below the one to confine
+                let index = self.active_layer;
+                if index == 0 {
+                    println!("no layer below to take the outline from");
+                } else if let Some((outline, _)) = closed_stroke(&self.layers[index - 1]) {
+                    let mask = get_inside_mask(self.width as u32, self.height as u32, &outline);
+                    self.replace_mask(Some(mask));
+                }
+            },
+            &Message::DeleteLayerMask => {
+                if self.layers[self.active_layer].mask.is_some() {
+                    self.replace_mask(None);
+                }
+            },
             &Message::ClearCanvasButton => {
-                self.image_cache = vec![0; (self.width * self.height * 4.0) as usize];
-                for l in &mut self.layers {
-                    l.clear();
+                let cleared: Vec<_> = self.layers.iter_mut().map(|l| {
+                    let (strokes, tiles) = l.clear();
+                    (l.id, strokes, tiles)
+                }).collect();
+                // an empty canvas leaves nothing to undo
+                if cleared.iter().any(|&(_, ref strokes, ref tiles)| !strokes.is_empty() || !tiles.is_empty()) {
+                    self.history.push(history::Command::Clear { layers: cleared });
                 }
+                // the overlay stays
+                let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                self.update_cache(&rect);
             },
-            &Message::OutputButton => {
-                let path = Path::new("out/out.ppm");
-                let mut file = match File::create(&path) {
-                    Ok(file) => file,
-                    Err(why) => panic!("couldn't create {}: {}", path.display(), why),
+            &Message::Undo => {
+                if !self.layers[self.active_layer].is_drawing() {
+                    if let Some(rect) = self.history.undo(&mut self.layers, &mut self.current_brush) {
+                        let active = self.history.active();
+                        self.reselect_layer(active);
+                        self.update_cache(&rect);
+                    }
+                }
+            },
+            &Message::Redo => {
+                if !self.layers[self.active_layer].is_drawing() {
+                    if let Some(rect) = self.history.redo(&mut self.layers, &mut self.current_brush) {
+                        let active = self.history.active();
+                        self.reselect_layer(active);
+                        self.update_cache(&rect);
+                    }
+                }
+            },
+            &Message::AddLayer => {
+                let id = self.new_layer_id();
+                let index = self.active_layer + 1;
+                self.insert_layer(index, Layer::new(id, &format!("Layer {}", id), self.width as u32, self.height as u32, 0));
+                self.active_layer = index;
+            },
+            &Message::DuplicateLayer => {
+                let id = self.new_layer_id();
+                let index = self.active_layer + 1;
+                let copy = {
+                    let l = &self.layers[self.active_layer];
+                    l.duplicate(id, &format!("{} copy", l.name))
                 };
-                file.write_all(self.output_ppm().as_bytes());
-                let cmd_res = Command::new("./bin/edge").output().expect("failed to execute process");
-//                println!("{}", String::from_utf8_lossy(&cmd_res.stdout));
-                let path_res_img = Path::new("out/matched.png");
-                let path_res = Path::new("out/result.txt");
-                let mut file = match File::open(&path_res) {
-                    Ok(file) => file,
-                    Err(why) => panic!("couldn't open {}: {}", path_res.display(), why),
+                self.insert_layer(index, copy);
+                self.active_layer = index;
+            },
+            &Message::DeleteLayer => {
+                if self.layers.len() > 1 {
+                    let index = self.active_layer;
+                    let layer = self.layers.remove(index);
+                    self.history.push(history::Command::SwapLayer { index: index, layer: Some(layer) });
+                    self.active_layer = index.max(1) - 1;
+                    let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                    self.update_cache(&rect);
+                } else {
+                    println!("can't delete the last layer");
+                }
+            },
+            &Message::MoveLayer(step) => {
+                let from = self.active_layer;
+                let to = saturate(from as i64 + step, 0, self.layers.len() as i64 - 1) as usize;
+                if to != from {
+                    let layer = self.layers.remove(from);
+                    self.layers.insert(to, layer);
+                    self.history.push(history::Command::MoveLayer { from: from, to: to });
+                    self.active_layer = to;
+                    let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                    self.update_cache(&rect);
+                }
+            },
+            &Message::MergeLayerDown => {
+                let index = self.active_layer;
+                if index > 0 && self.layers[index - 1].clip && !self.layers[index].clip {
+                    // the merged layer could not be both clipped and not
+                    println!("can't merge onto a clipped layer");
+                } else if index > 0 {
+                    let upper = self.layers.remove(index);
+                    let (strokes, tiles, opacity, mask) = self.layers[index - 1].merge(&upper);
+                    self.history.push(history::Command::MergeDown {
+                        index: index, upper: Some(upper), strokes: strokes, tiles: tiles, opacity: opacity, mask: mask
+                    });
+                    self.active_layer = index - 1;
+                    let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                    self.update_cache(&rect);
+                }
+            },
+            &Message::FlattenLayers => {
+                let id = self.new_layer_id();
+                let flat = Layer::from_image(id, &self.layers[0].name.clone(), self.flatten());
+                let layers = mem::replace(&mut self.layers, vec![flat]);
+                self.history.push(history::Command::Flatten { layers: layers });
+                self.active_layer = 0;
+                let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                self.update_cache(&rect);
+            },
+            &Message::RenameLayer(ref name) => {
+                let layer = &mut self.layers[self.active_layer];
+                if layer.name != *name {
+                    let old = mem::replace(&mut layer.name, name.clone());
+                    self.history.push_burst(history::Command::RenameLayer { layer: layer.id, name: old },
+                                            history::Burst::LayerName(layer.id));
+                }
+            },
+            &Message::LayerBlendSelect(mode) => {
+                let layer = &mut self.layers[self.active_layer];
+                if layer.blend_mode != mode {
+                    let old = mem::replace(&mut layer.blend_mode, mode);
+                    self.history.push(history::Command::LayerBlend { layer: layer.id, mode: old });
+                }
+                let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                self.update_cache(&rect);
+            },
+            &Message::SelectLayer(id) => {
+                if !self.layers[self.active_layer].is_drawing() {
+                    self.reselect_layer(id);
+                }
+            },
+            &Message::SelectLayerStep(step) => {
+                if !self.layers[self.active_layer].is_drawing() {
+                    let index = saturate(self.active_layer as i64 + step, 0, self.layers.len() as i64 - 1);
+                    self.active_layer = index as usize;
+                }
+            },
+            &Message::LayerVisible(id, visible) => {
+                if let Some(index) = layer_index(&self.layers, id) {
+                    self.layers[index].visible = visible;
+                    let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                    self.update_cache(&rect);
+                }
+            },
+            &Message::Save => {
+                let path = self.document_path.clone();
+                self.save_document(&path);
+            },
+            &Message::SaveDocument(ref path) => {
+                self.save_document(path);
+                self.document_path = path.clone();
+            },
+            &Message::OpenDocument(ref path) => {
+                match File::open(&path).and_then(|file| document::load(&mut BufReader::new(file))) {
+                    Ok(doc) => {
+                        self.load_document(doc);
+                        self.document_path = path.clone();
+                    },
+                    Err(why) => println!("couldn't open {}: {}", path, why),
+                }
+            },
+            &Message::ExportPng(ref path) => {
+                let path = Path::new(path);
+                let image = self.flatten();
+                if let Err(why) = image_io::save(&path, &image) {
+                    println!("couldn't export {}: {}", path.display(), why);
+                }
+            },
+            &Message::ImportPngLayer(ref path) => {
+                let path = Path::new(path);
+                match image_io::open(&path) {
+                    Ok(image) => {
+                        // placed right below the active layer so that it can be traced
+                        let id = self.new_layer_id();
+                        let name = path.file_stem().map_or("Import".to_string(), |s| s.to_string_lossy().into_owned());
+                        let layer = Layer::from_image(id, &name, image.resize_canvas(self.width as u32, self.height as u32).convert());
+                        let index = self.active_layer;
+                        self.insert_layer(index, layer);
+                    },
+                    Err(why) => println!("couldn't import {}: {}", path.display(), why),
+                }
+            },
+            &Message::TargetImage(ref path) => {
+                self.target_image = path.clone();
+            },
+            &Message::OutputButton => {
+                let path = Path::new(&self.target_image);
+                let target = match image_io::open(&path) {
+                    Ok(image) => image,
+                    Err(why) => {
+                        println!("couldn't open {}: {}", path.display(), why);
+                        return;
+                    },
                 };
-                let mut score_str = String::new();
-                file.read_to_string(&mut score_str);
-//                println!("{}", score_str);
-                score_str.pop();
-                let score: f64 = score_str.parse().unwrap();
-                println!("score: {}", score);
-
-                Command::new("open").arg("out/matched.png").output().expect("failed to execute process");
+                let drawing = self.flatten().convert();
+                let result = scoring::match_edges(&drawing, &target);
+                let mut overlay = Layer::from_image(0, "Overlay", result.overlay.convert());
+                overlay.set_visible(self.show_overlay.get());
+                self.overlay = Some(overlay);
+                let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                self.update_cache(&rect);
+                self.score = Some(result.score);
+            },
+            &Message::OverlayVisible(visible) => {
+                self.show_overlay.set(visible);
+                if let Some(ref mut overlay) = self.overlay {
+                    overlay.set_visible(visible);
+                }
+                let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+                self.update_cache(&rect);
             },
             _ => (),
         }
-        if let &mut HandlerType::Area(ref area) = widget_handler {
-            area.queue_redraw_all();
-        }
+        // the brush may also have changed through undo
+        self.brush_size.set(self.current_brush.size as i64);
+        self.tool.set(tool_name(&self.current_brush).to_string());
+        self.history.set_active(self.layers[self.active_layer].id);
+        self.update_layer_properties();
     }
-}
 
-impl AreaCallbacks for CanvasModel {
-    fn on_draw(&mut self, area: &AreaHandler, area_draw_params: &AreaDrawParams) {
-        // TODO: reduce copy cost (it may require changing libui)
-        let mut image = ui::Image::new(self.width, self.height);
-        image.data = self.image_cache.to_vec();
-        area_draw_params.context.draw_image(0.0, 0.0, image.width, image.height, &mut image);
-    }
-
-    fn on_mouse_event(&mut self, area: &AreaHandler, area_mouse_event: &AreaMouseEvent) {
-        let dragging = (area_mouse_event.held_1_to_64 != 0) | (area_mouse_event.down != 0);
-        let point = StrokePoint {
-            x: area_mouse_event.x,
-            y: area_mouse_event.y,
-            pressure: 0.0,
-            tilt_x: 0.0,
-            tilt_y: 0.0,
-            timestamp: time::now().to_timespec().sec,
-            dragging: dragging,
+    // Pen input on the active layer. Returns the area of the composited image that changed.
+    pub fn mouse_event(&mut self, point: StrokePoint) -> Option<Rect<i32>> {
+        let point = if point.dragging {
+            match self.stabilizer.filter(point) {
+                Some(point) => point,
+                None => return None,
+            }
+        } else {
+            self.stabilizer.reset();
+            point
         };
-        if let Some(rect) = self.layers[self.active_layer].mouse_event(&self.current_brush, point) {
-            self.update_cache(&rect);
-            area.queue_redraw_all();
+        let mut rect = self.layers[self.active_layer].mouse_event(&self.current_brush, point);
+        if rect.is_none() {
+            rect = self.layers[self.active_layer].smooth_finished_stroke(self.smoothing);
+        }
+        if let Some(ref rect) = rect {
+            self.update_cache(rect);
         }
+        if let Some((stroke, tiles)) = self.layers[self.active_layer].take_finished_edit() {
+            let layer = self.layers[self.active_layer].id;
+            self.history.push(history::Command::AddStroke { layer: layer, stroke: stroke, tiles: tiles });
+        }
+        rect
     }
-}
 
-impl CanvasModel {
-    pub fn new(w: f64, h: f64) -> CanvasModel {
-        CanvasModel {
-            layers: vec![Layer::new(w as u32, h as u32, 0), Layer::new(w as u32, h as u32, 0)],
+    pub fn new(w: f64, h: f64) -> Canvas {
+        let mut canvas = Canvas {
+            layers: vec![Layer::new(1, "Background", w as u32, h as u32, 0),
+                         Layer::new(2, "Layer 2", w as u32, h as u32, 0)],
             current_brush: Brush::new(),
             image_cache: vec![0; (w * h * 4.0) as usize],
             active_layer: 1,
+            next_layer_id: 3,
             width: w,
             height: h,
+            history: History::new(),
+            overlay: None,
+            score: None,
+            show_overlay: Property::new(true),
+            brush_size: Property::new(Brush::new().size as i64),
+            tool: Property::new(tool_name(&Brush::new()).to_string()),
+            layer_rows: (0..LAYER_ROWS).map(|_| LayerRow::new()).collect(),
+            layer_name: Property::new(String::new()),
+            layer_blend: Property::new(0),
+            layer_opacity: Property::new(100),
+            layer_clip: Property::new(false),
+            layer_masked: Property::new(false),
+            target_image: DEFAULT_TARGET_IMAGE.to_string(),
+            document_path: DEFAULT_DOCUMENT.to_string(),
+            stabilizer: Stabilizer::new(StabilizerKind::None),
+            smoothing: Smoothing::None,
+        };
+        canvas.history.set_active(canvas.layers[canvas.active_layer].id);
+        canvas.update_layer_properties();
+        canvas
+    }
+
+    pub fn width(&self) -> f64 {
+        self.width
+    }
+
+    pub fn height(&self) -> f64 {
+        self.height
+    }
+
+    // The composited image as shown on screen, overlay included, in
+    // straight alpha.
+    pub fn frame(&self) -> Vec<u8> {
+        straight_bytes(&self.image_cache)
+    }
+
+    pub fn frame_image(&self) -> CanvasImage {
+        CanvasImage::from_data(self.width as u32, self.height as u32, convert_data(&self.image_cache))
+    }
+
+    // The score of a matching done since the last call.
+    pub fn take_score(&mut self) -> Option<f64> {
+        self.score.take()
+    }
+
+    pub fn brush_size(&self) -> Property<i64> {
+        self.brush_size.clone()
+    }
+
+    pub fn show_overlay(&self) -> Property<bool> {
+        self.show_overlay.clone()
+    }
+
+    pub fn tool(&self) -> Property<String> {
+        self.tool.clone()
+    }
+
+    pub fn layer_rows(&self) -> Vec<LayerRow> {
+        self.layer_rows.clone()
+    }
+
+    pub fn layer_name(&self) -> Property<String> {
+        self.layer_name.clone()
+    }
+
+    pub fn layer_blend(&self) -> Property<i64> {
+        self.layer_blend.clone()
+    }
+
+    pub fn layer_opacity(&self) -> Property<i64> {
+        self.layer_opacity.clone()
+    }
+
+    pub fn layer_clip(&self) -> Property<bool> {
+        self.layer_clip.clone()
+    }
+
+    pub fn layer_masked(&self) -> Property<bool> {
+        self.layer_masked.clone()
+    }
+
+    pub fn layers(&self) -> &Vec<Layer> {
+        &self.layers
+    }
+
+    pub fn active_layer(&self) -> usize {
+        self.active_layer
+    }
+
+    fn new_layer_id(&mut self) -> u32 {
+        let id = self.next_layer_id;
+        self.next_layer_id += 1;
+        id
+    }
+
+    // Adds the layer at `index`; the active layer stays the same one.
+    fn insert_layer(&mut self, index: usize, layer: Layer) {
+        self.layers.insert(index, layer);
+        self.history.push(history::Command::SwapLayer { index: index, layer: None });
+        if index <= self.active_layer {
+            self.active_layer += 1;
         }
+        let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+        self.update_cache(&rect);
     }
-    pub fn update_cache(&mut self, rect: &Rect<i32>) {
-        for l in &self.layers {
-            l.composite(&mut self.image_cache, &rect);
+
+    // Makes the layer with the given id active, or keeps the position when
+    // it is gone.
+    fn reselect_layer(&mut self, id: u32) {
+        self.active_layer = match layer_index(&self.layers, id) {
+            Some(index) => index,
+            None => self.active_layer.min(self.layers.len() - 1),
+        };
+    }
+
+    // Puts `mask` on the active layer, remembering the old one for undo.
+    fn replace_mask(&mut self, mask: Option<LayerImage>) {
+        let layer = &mut self.layers[self.active_layer];
+        let old = mem::replace(&mut layer.mask, mask);
+        self.history.push(history::Command::LayerMask { layer: layer.id, mask: old });
+        let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+        self.update_cache(&rect);
+    }
+
+    fn update_layer_properties(&mut self) {
+        for (k, row) in self.layer_rows.iter().enumerate() {
+            match self.layers.len().checked_sub(k + 1) {
+                Some(i) => {
+                    let l = &self.layers[i];
+                    row.id.set(l.id as i64);
+                    row.label.set(format!("{} {}", if i == self.active_layer { ">" } else { " " }, l.name));
+                    row.visible.set(l.visible);
+                },
+                None => {
+                    row.id.set(0);
+                    row.label.set(String::new());
+                    row.visible.set(false);
+                },
+            }
+        }
+        let active = &self.layers[self.active_layer];
+        self.layer_name.set(active.name.clone());
+        let blend = blend::MODES.iter().position(|&(mode, _)| mode == active.blend_mode).unwrap_or(0);
+        self.layer_blend.set(blend as i64);
+        self.layer_opacity.set((active.opacity * 100.0).round() as i64);
+        self.layer_clip.set(active.clip);
+        self.layer_masked.set(active.mask.is_some());
+    }
+
+    fn save_document(&self, path: &str) {
+        let result = File::create(&path).and_then(|file| {
+            document::save(&mut BufWriter::new(file), self.width as u32, self.height as u32,
+                           self.active_layer, &self.layers)
+        });
+        if let Err(why) = result {
+            println!("couldn't save {}: {}", path, why);
         }
     }
 
-    fn output_ppm(&mut self) -> String {
-        let mut buff = String::new();
+    fn load_document(&mut self, doc: document::Document) {
+        self.width = doc.width as f64;
+        self.height = doc.height as f64;
+        self.layers = doc.layers;
+        self.active_layer = doc.active_layer;
+        self.next_layer_id = self.layers.iter().map(|l| l.id).max().unwrap_or(0) + 1;
+        self.image_cache = vec![0; (doc.width * doc.height * 4) as usize];
+        self.history.clear();
+        self.overlay = None;
+        let rect = Rect::new(0, 0, doc.width as i32, doc.height as i32);
+        self.update_cache(&rect);
+    }
+
+    // The layers composited without the overlay.
+    pub fn flatten(&self) -> LayerImage {
+        let mut data = vec![0; (self.width * self.height * 4.0) as usize];
+        let rect = Rect::new(0, 0, self.width as i32, self.height as i32);
+        composite_layers(&self.layers, &mut data, &rect);
+        LayerImage::from_data(self.width as u32, self.height as u32, data)
+    }
+
+    pub fn update_cache(&mut self, rect: &Rect<i32>) {
+        // start from a transparent background so that removed pixels do not linger
         let iw = self.width as i32;
-        let ih = self.height as i32;
-        buff.push_str("P3\n");
-        buff.push_str(format!("{} {}\n255\n", self.width as i32, self.height as i32).as_str());
-        let mut brush = Brush::new();
-        brush.size = 1.0;
-        brush.color = Color::new(1.0, 1.0, 1.0, 1.0);
-        let mut data = vec![0; (iw * ih * 4) as usize];
-        for l in &mut self.layers {
-            l.image.draw_stroke(&l.strokes, &brush);
-            l.composite(&mut data, &Rect::new(0, 0, iw, ih));
-        }
-        for y in 0..(self.height as usize) {
-            for x in 0..(self.width as usize) {
-                let i = (y * iw as usize + x) * 4;
-                buff.push_str(format!("{} {} {}\n", data[i+0], data[i+1], data[i+2]).as_str());
+        for y in saturate(rect.lt_y, 0, self.height as i32)..saturate(rect.rb_y, 0, self.height as i32) {
+            let i = ((y * iw + saturate(rect.lt_x, 0, iw)) * 4) as usize;
+            let j = ((y * iw + saturate(rect.rb_x, 0, iw)) * 4) as usize;
+            for v in &mut self.image_cache[i..j] {
+                *v = 0;
             }
         }
-        buff
+        composite_layers(&self.layers, &mut self.image_cache, rect);
+        if let Some(ref overlay) = self.overlay {
+            if overlay.visible() {
+                overlay.composite(&mut self.image_cache, &rect, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(id: u32, straight: [u8; 4]) -> Layer {
+        Layer::from_image(id, "l", CanvasImage::from_straight(1, 1, straight.to_vec()))
+    }
+
+    // The composite in 8 bit.
+    fn composited(layers: Vec<Layer>) -> Vec<u8> {
+        let mut data = vec![0; 4];
+        composite_layers(&layers, &mut data, &Rect::new(0, 0, 1, 1));
+        LayerImage::from_data(1, 1, data).convert::<u8>().data().clone()
+    }
+
+    #[test]
+    fn premultiply_round_trip() {
+        let mut p = [255, 128, 0, 128, 10, 20, 30, 0];
+        premultiply(&mut p);
+        assert_eq!(p, [128, 64, 0, 128, 0, 0, 0, 0]);
+        assert_eq!(straight_bytes(&p), [255, 128, 0, 128, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn translucent_layer_keeps_its_color() {
+        // over nothing a half transparent white must not turn gray
+        let data = composited(vec![layer(1, [255, 255, 255, 128])]);
+        assert_eq!(data, [128, 128, 128, 128]);
+        let image = CanvasImage::from_data(1, 1, data);
+        assert_eq!(image.straight_data(), [255, 255, 255, 128]);
+    }
+
+    #[test]
+    fn source_over_reference_values() {
+        // half red over opaque blue
+        let data = composited(vec![layer(1, [0, 0, 255, 255]), layer(2, [255, 0, 0, 128])]);
+        assert_eq!(data, [128, 0, 127, 255]);
+        // half red over half blue: straight (2/3, 0, 1/3) at 3/4
+        let data = composited(vec![layer(1, [0, 0, 255, 128]), layer(2, [255, 0, 0, 128])]);
+        assert_eq!(data, [128, 0, 64, 192]);
+        assert_eq!(CanvasImage::from_data(1, 1, data).straight_data(), [170, 0, 85, 192]);
+    }
+
+    #[test]
+    fn opacity_scales_the_premultiplied_pixel() {
+        let mut top = layer(2, [255, 255, 255, 255]);
+        top.set_opacity(0.5);
+        let data = composited(vec![layer(1, [0, 0, 0, 255]), top]);
+        assert_eq!(data, [128, 128, 128, 255]);
+    }
+
+    fn half_mask() -> Option<LayerImage> {
+        Some(CanvasImage::<u8>::from_straight(1, 1, vec![128, 128, 128, 255]).convert())
+    }
+
+    #[test]
+    fn mask_scales_like_opacity() {
+        let mut top = layer(2, [255, 255, 255, 255]);
+        *top.mask_mut() = half_mask();
+        assert_eq!(composited(vec![layer(1, [0, 0, 0, 255]), top]), [128, 128, 128, 255]);
+        let mut top = layer(2, [255, 255, 255, 255]);
+        *top.mask_mut() = half_mask();
+        top.set_opacity(0.5);
+        assert_eq!(composited(vec![layer(1, [0, 0, 0, 255]), top]), [64, 64, 64, 255]);
+    }
+
+    #[test]
+    fn clipped_layer_takes_the_coverage_below() {
+        let mut top = layer(2, [255, 0, 0, 255]);
+        top.set_clip(true);
+        // over nothing there is nothing to clip to
+        assert_eq!(composited(vec![layer(1, [0, 0, 0, 0]), top]), [0, 0, 0, 0]);
+        // a half covered base shows half of the clipped layer: half red
+        // over half blue
+        let mut top = layer(2, [255, 0, 0, 255]);
+        top.set_clip(true);
+        let mut base = layer(1, [0, 0, 255, 255]);
+        base.set_opacity(0.5);
+        assert_eq!(composited(vec![base, top]), [128, 0, 64, 191]);
+        // and none of it when the base is hidden
+        let mut top = layer(2, [255, 0, 0, 255]);
+        top.set_clip(true);
+        let mut base = layer(1, [0, 0, 255, 255]);
+        base.set_visible(false);
+        assert_eq!(composited(vec![base, top]), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn merge_keeps_the_image() {
+        let mut canvas = Canvas::new(1.0, 1.0);
+        let mut lower = layer(2, [0, 0, 255, 255]);
+        lower.set_opacity(0.5);
+        *lower.mask_mut() = half_mask();
+        let mut upper = layer(3, [255, 0, 0, 128]);
+        upper.set_opacity(0.5);
+        canvas.layers = vec![layer(1, [0, 255, 0, 255]), lower, upper];
+        canvas.active_layer = 2;
+        canvas.update(&Message::LayerVisible(1, true)); // recomposites
+        let before = canvas.frame();
+
+        canvas.update(&Message::MergeLayerDown);
+        assert_eq!(canvas.layers().len(), 2);
+        assert_eq!(canvas.layers()[1].opacity(), 1.0);
+        assert!(canvas.layers()[1].mask().is_none());
+        let after = canvas.frame();
+        for k in 0..4 {
+            assert!((before[k] as i32 - after[k] as i32).abs() <= 1, "{:?} {:?}", before, after);
+        }
+
+        // undone, the lower layer has its own opacity and mask back
+        canvas.update(&Message::Undo);
+        assert_eq!(canvas.layers().len(), 3);
+        assert_eq!(canvas.layers()[1].opacity(), 0.5);
+        assert!(canvas.layers()[1].mask().is_some());
+        assert_eq!(canvas.frame(), before);
+        canvas.update(&Message::Redo);
+        assert_eq!(canvas.frame(), after);
+    }
+
+    #[test]
+    fn no_merging_onto_a_clipped_layer() {
+        let mut canvas = Canvas::new(1.0, 1.0);
+        canvas.update(&Message::SelectLayerStep(-1));
+        canvas.update(&Message::AddLayer);
+        canvas.update(&Message::LayerClip(true));
+        canvas.update(&Message::SelectLayerStep(1));
+        canvas.update(&Message::MergeLayerDown);
+        assert_eq!(canvas.layers().len(), 3);
+    }
+
+    #[test]
+    fn dabs_build_up_without_darkening() {
+        let mut image = CanvasImage::<u8>::new(1, 1, 0);
+        let white = Color::new(1.0, 1.0, 1.0, 1.0);
+        image.blend_pixel(0, &white, 0.5, BrushBlend::Normal);
+        image.blend_pixel(0, &white, 0.5, BrushBlend::Normal);
+        assert_eq!(image.data(), &vec![192, 192, 192, 192]);
+        let c = image.at(0, 0);
+        assert_eq!((c.r, c.g, c.b, c.a), (255, 255, 255, 192));
+        image.blend_pixel(0, &white, 0.5, BrushBlend::Erase);
+        assert_eq!(image.data(), &vec![96, 96, 96, 96]);
+    }
+
+    #[test]
+    fn brush_blends_reference_values() {
+        // paint straight (0.5, 1, 0) at half alpha onto straight (0.8, 0.4, 0.2) at half alpha
+        let paint = Color::new(0.5, 1.0, 0.0, 1.0);
+        let blended = |blend: BrushBlend, back: [f32; 4]| {
+            let mut image = CanvasImage::<f32>::new(1, 1, 0);
+            image.set_pixel(0, 0, back);
+            image.blend_pixel(0, &paint, 0.5, blend);
+            image.pixel(0, 0)
+        };
+        let back = [0.4, 0.2, 0.1, 0.5];
+        let expected = [
+            (BrushBlend::Normal, [0.45, 0.6, 0.05, 0.75]),
+            (BrushBlend::Multiply, [0.425, 0.45, 0.05, 0.75]),
+            (BrushBlend::Screen, [0.55, 0.6, 0.1, 0.75]),
+            (BrushBlend::Behind, [0.525, 0.45, 0.1, 0.75]),
+            (BrushBlend::LockAlpha, [0.325, 0.35, 0.05, 0.5]),
+            (BrushBlend::Erase, [0.2, 0.1, 0.05, 0.25]),
+        ];
+        for &(blend, e) in expected.iter() {
+            let p = blended(blend, back);
+            assert!((0..4).all(|k| (p[k] - e[k]).abs() < 1e-6), "{:?}: {:?}", blend, p);
+        }
+        // on an empty pixel multiply and screen have nothing to mix with
+        let normal = blended(BrushBlend::Normal, [0.0; 4]);
+        assert_eq!(blended(BrushBlend::Multiply, [0.0; 4]), normal);
+        assert_eq!(blended(BrushBlend::Screen, [0.0; 4]), normal);
+        // and nothing shows behind an opaque one or on a transparent one
+        assert_eq!(blended(BrushBlend::Behind, [0.8, 0.4, 0.2, 1.0]), [0.8, 0.4, 0.2, 1.0]);
+        assert_eq!(blended(BrushBlend::LockAlpha, [0.0; 4]), [0.0; 4]);
+    }
+
+    #[test]
+    fn faint_dabs_add_up_in_16_bit() {
+        let black = Color::new(0.0, 0.0, 0.0, 1.0);
+        let mut image8 = CanvasImage::<u8>::new(1, 1, 0);
+        let mut image16 = LayerImage::new(1, 1, 0);
+        for _ in 0..200 {
+            image8.blend_pixel(0, &black, 0.001, BrushBlend::Normal);
+            image16.blend_pixel(0, &black, 0.001, BrushBlend::Normal);
+        }
+        // each dab is less than half a step of 8 bit and rounds away
+        assert_eq!(image8.data()[3], 0);
+        let expected = 1.0 - 0.999f64.powi(200);
+        assert!((image16.data()[3].to_f64() - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn normal_field_is_signed() {
+        let mut field = CanvasImage::<f32>::new(2, 1, 0);
+        field.set_pixel(0, 0, [-1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(field.pixel(0, 0), [-1.0, 0.0, 0.0, 1.0]);
+        let image = normal_map(&field).convert::<u8>();
+        assert_eq!(image.data(), &vec![0, 128, 128, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn closing_keeps_the_normal_field() {
+        let mut canvas = Canvas::new(32.0, 32.0);
+        for k in 0..=24 {
+            let (sin, cos) = (k as f64 * ::std::f64::consts::PI / 12.0).sin_cos();
+            canvas.mouse_event(point(16.0 + 10.0 * cos, 16.0 + 10.0 * sin, true));
+        }
+        canvas.mouse_event(point(26.0, 16.0, false));
+        canvas.update(&Message::StrokeCloseButton);
+        let field = canvas.layers()[1].normals().unwrap();
+        assert_eq!(field.pixel(1, 1), [0.0; 4]);
+        for &(x, y) in [(9, 16), (23, 16), (16, 9), (16, 23), (16, 16)].iter() {
+            let n = field.pixel(x, y);
+            assert_eq!(n[3], 1.0);
+            assert!(((n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt() - 1.0).abs() < 1e-4);
+        }
+        // mirrored across the center, the sign flips
+        let (left, right) = (field.pixel(9, 16), field.pixel(23, 16));
+        assert!(left[0] * right[0] < 0.0 && left[0].abs() > 0.1);
+        let (top, bottom) = (field.pixel(16, 9), field.pixel(16, 23));
+        assert!(top[1] * bottom[1] < 0.0 && top[1].abs() > 0.1);
+    }
+
+    fn point(x: f64, y: f64, dragging: bool) -> StrokePoint {
+        StrokePoint { x: x, y: y, pressure: 0.0, tilt_x: 0.0, tilt_y: 0.0, timestamp: (x * 10.0) as i64, dragging: dragging }
+    }
+
+    #[test]
+    fn smoothed_stroke_is_one_undo_step() {
+        let mut canvas = Canvas::new(32.0, 16.0);
+        canvas.update(&Message::SmoothingSelect(Smoothing::CatmullRom));
+        for &(x, y) in [(2.0, 4.0), (12.0, 10.0), (22.0, 4.0), (30.0, 10.0)].iter() {
+            canvas.mouse_event(point(x, y, true));
+        }
+        canvas.mouse_event(point(30.0, 10.0, false));
+        let drawn = canvas.frame();
+        // the stroke keeps the smoothed points, drawn once
+        assert!(canvas.layers()[1].strokes()[0].points.len() > 5);
+        canvas.update(&Message::Undo);
+        assert!(canvas.layers()[1].strokes().is_empty());
+        assert!(canvas.frame().iter().all(|&v| v == 0));
+        canvas.update(&Message::Redo);
+        assert_eq!(canvas.frame(), drawn);
+    }
+
+    #[test]
+    fn layer_rows_follow_the_stack() {
+        let mut canvas = Canvas::new(4.0, 4.0);
+        let rows = canvas.layer_rows();
+        let labels = || rows.iter().map(|r| r.label.get()).collect::<Vec<_>>();
+        assert_eq!(&labels()[..3], &["> Layer 2", "  Background", ""]);
+        assert_eq!(rows[2].id.get(), 0);
+
+        // what the select button and checkbox of the bottom row send
+        canvas.update(&Message::SelectLayer(rows[1].id.get() as u32));
+        assert_eq!(canvas.active_layer(), 0);
+        canvas.update(&Message::LayerVisible(rows[1].id.get() as u32, false));
+        assert!(!rows[1].visible.get() && rows[0].visible.get());
+        assert!(!canvas.layers()[0].visible());
+
+        canvas.update(&Message::AddLayer);
+        assert_eq!(&labels()[..3], &["  Layer 2", "> Layer 3", "  Background"]);
+        assert!(!rows[2].visible.get());
+    }
+
+    // Ids, names and stroke counts bottom to top, and the active layer.
+    fn stack(canvas: &Canvas) -> (Vec<(u32, String, usize)>, usize) {
+        (canvas.layers().iter().map(|l| (l.id(), l.name().to_string(), l.strokes().len())).collect(), canvas.active_layer())
+    }
+
+    #[test]
+    fn layer_operations_undo_and_redo() {
+        let operations = [Message::AddLayer, Message::DeleteLayer, Message::DuplicateLayer,
+                          Message::MoveLayer(-1), Message::RenameLayer("Ink".to_string())];
+        for operation in operations.iter() {
+            let mut canvas = Canvas::new(8.0, 8.0);
+            for &(x, d) in [(1.0, true), (6.0, true), (0.0, false)].iter() {
+                canvas.mouse_event(point(x, 4.0, d));
+            }
+            let (before, frame) = (stack(&canvas), canvas.frame());
+            canvas.update(operation);
+            let after = stack(&canvas);
+            assert!(after != before, "{:?} changed nothing", operation);
+            canvas.update(&Message::Undo);
+            assert_eq!(stack(&canvas), before, "undoing {:?}", operation);
+            assert_eq!(canvas.frame(), frame);
+            canvas.update(&Message::Redo);
+            assert_eq!(stack(&canvas), after, "redoing {:?}", operation);
+        }
+    }
+
+    #[test]
+    fn import_is_an_undoable_layer() {
+        let mut canvas = Canvas::new(8.0, 8.0);
+        for &(x, d) in [(1.0, true), (6.0, true), (0.0, false)].iter() {
+            canvas.mouse_event(point(x, 4.0, d));
+        }
+        let path = ::std::env::temp_dir().join(format!("sketch_book_import_{}.png", ::std::process::id()));
+        image_io::save(&path, &CanvasImage::<u8>::new(4, 4, 255)).unwrap();
+        canvas.update(&Message::ImportPngLayer(path.to_string_lossy().into_owned()));
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(canvas.layers().len(), 3);
+
+        // the imported layer has no strokes to close
+        canvas.update(&Message::SelectLayerStep(-1));
+        assert!(canvas.layers()[canvas.active_layer()].strokes().is_empty());
+        canvas.update(&Message::StrokeCloseButton);
+        assert_eq!(canvas.layers().len(), 3);
+
+        canvas.update(&Message::Undo);
+        assert_eq!(canvas.layers().len(), 2);
+        // the stroke from before the import is still in the history
+        canvas.update(&Message::Undo);
+        assert!(canvas.layers().iter().all(|l| l.strokes().is_empty()));
+    }
+
+    #[test]
+    fn clearing_keeps_the_overlay() {
+        let mut canvas = Canvas::new(16.0, 16.0);
+        for &(x, d) in [(3.0, true), (12.0, true), (0.0, false)].iter() {
+            canvas.mouse_event(point(x, 8.0, d));
+        }
+        let path = ::std::env::temp_dir().join(format!("sketch_book_target_{}.png", ::std::process::id()));
+        // black below the middle, white above
+        let target: Vec<u8> = (0..16 * 16).flat_map(|i| if i < 16 * 8 { vec![255; 4] } else { vec![0, 0, 0, 255] }).collect();
+        image_io::save(&path, &CanvasImage::<u8>::from_straight(16, 16, target)).unwrap();
+        canvas.update(&Message::TargetImage(path.to_string_lossy().into_owned()));
+        canvas.update(&Message::OutputButton);
+        ::std::fs::remove_file(&path).unwrap();
+
+        canvas.update(&Message::ClearCanvasButton);
+        assert!(canvas.layers().iter().all(|l| l.strokes().is_empty()));
+        assert!(canvas.frame().iter().any(|&v| v != 0));
+        canvas.update(&Message::OverlayVisible(false));
+        assert!(canvas.frame().iter().all(|&v| v == 0));
+
+        // clearing again adds no step, so one undo brings the stroke back
+        canvas.update(&Message::ClearCanvasButton);
+        canvas.update(&Message::Undo);
+        assert_eq!(canvas.layers()[1].strokes().len(), 1);
     }
 }
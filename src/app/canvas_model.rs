@@ -0,0 +1,234 @@
+extern crate ui;
+extern crate time;
+
+use std::path::Path;
+use std::time::Instant;
+
+use message::{Message, Topic};
+use reactive;
+use reactive::property::Property;
+use reactive::widget::{HandlerType, Model, AreaDrawParams, AreaMouseEvent, AreaKeyEvent, AreaHandler, AreaCallbacks};
+
+use app::canvas::{Canvas, CanvasImage, LayerRow};
+use app::stroke::StrokePoint;
+use app::keymap::{Keymap, KeyChord, KEYMAP_FILE};
+use app::render::{Renderer, Step};
+use app::session;
+use app::session::{Player, Recorder};
+use app::dynamics;
+
+// The canvas as a libui component: feeds it messages and mouse input and
+// draws its frame into the area.
+pub struct CanvasModel {
+    canvas: Canvas,
+    keymap: Keymap,
+    area: Option<AreaHandler>,
+    recorder: Option<Recorder>,
+    synthetic_pressure: bool,
+    stroke_start: Option<i64>, // ms
+    playback: Option<(Player, Instant)>, // and when it started
+}
+
+impl Model<Message> for CanvasModel {
+    fn update(&mut self, message: &Message, widget_handler: &mut HandlerType) {
+        match message {
+            &Message::CanvasMouseEvent { .. } => self.record(&Step::Message(message.clone())),
+            &Message::StartRecording(ref path) => {
+                self.stop_recording();
+                match Recorder::create(Path::new(path)) {
+                    Ok(recorder) => self.recorder = Some(recorder),
+                    Err(why) => println!("couldn't record to {}: {}", path, why),
+                }
+            },
+            &Message::StopRecording => self.stop_recording(),
+            &Message::SyntheticPressure(on) => self.synthetic_pressure = on,
+            &Message::PlayRecording(ref path, speed) => {
+                // waiting here would block the event loop, each redraw plays what is due instead
+                match session::load(Path::new(path)) {
+                    Ok(events) => {
+                        self.playback = Some((Player::new(events).speed(speed), Instant::now()));
+                        self.play_due();
+                    },
+                    Err(why) => println!("couldn't play {}: {}", path, why),
+                }
+            },
+            _ => (),
+        }
+        self.update_canvas(message);
+        if let &mut HandlerType::Area(ref area) = widget_handler {
+            area.queue_redraw_all();
+        }
+    }
+
+    fn subscriptions(&self) -> Vec<Topic> {
+        vec![Topic::Canvas, Topic::Brush]
+    }
+}
+
+impl AreaCallbacks for CanvasModel {
+    fn on_attach(&mut self, area: &AreaHandler) {
+        self.area = Some(area.clone());
+    }
+
+    fn on_draw(&mut self, area: &AreaHandler, area_draw_params: &AreaDrawParams) {
+        if self.playback.is_some() {
+            self.play_due();
+            area.queue_redraw_all();
+        }
+        // TODO: reduce copy cost (it may require changing libui)
+        let mut image = ui::Image::new(self.canvas.width(), self.canvas.height());
+        image.data = self.canvas.frame();
+        area_draw_params.context.draw_image(0.0, 0.0, image.width, image.height, &mut image);
+    }
+
+    fn on_mouse_event(&mut self, area: &AreaHandler, area_mouse_event: &AreaMouseEvent) {
+        let dragging = (area_mouse_event.held_1_to_64 != 0) | (area_mouse_event.down != 0);
+        let now = time::now().to_timespec();
+        let timestamp = now.sec * 1000 + (now.nsec / 1_000_000) as i64;
+        // libui reports no pressure; leaving it at 0 lets the speed stand in
+        let pressure = match (self.synthetic_pressure, dragging) {
+            (true, true) => dynamics::synthetic_pressure(timestamp - *self.stroke_start.get_or_insert(timestamp)),
+            _ => 0.0,
+        };
+        if !dragging {
+            self.stroke_start = None;
+        }
+        let point = StrokePoint {
+            x: area_mouse_event.x,
+            y: area_mouse_event.y,
+            pressure: pressure,
+            tilt_x: 0.0,
+            tilt_y: 0.0,
+            timestamp: timestamp,
+            dragging: dragging,
+        };
+        self.record(&Step::Point(point.clone()));
+        if self.canvas.mouse_event(point).is_some() {
+            area.queue_redraw_all();
+        }
+    }
+
+    fn on_key_event(&mut self, area: &AreaHandler, area_key_event: &AreaKeyEvent) -> bool {
+        if area_key_event.up {
+            return false;
+        }
+        let message = match self.keymap.lookup(KeyChord::from_event(area_key_event)) {
+            Some(message) => message.clone(),
+            None => return false,
+        };
+        reactive::emit(message);
+        true
+    }
+}
+
+impl Renderer for CanvasModel {
+    fn send(&mut self, message: &Message) {
+        self.update_canvas(message);
+        self.redraw();
+    }
+
+    fn stroke(&mut self, point: StrokePoint) {
+        if self.canvas.mouse_event(point).is_some() {
+            self.redraw();
+        }
+    }
+
+    fn frame(&self) -> CanvasImage {
+        self.canvas.frame_image()
+    }
+}
+
+impl CanvasModel {
+    pub fn new(w: f64, h: f64) -> CanvasModel {
+        CanvasModel {
+            canvas: Canvas::new(w, h),
+            keymap: Keymap::load(Path::new(KEYMAP_FILE)),
+            area: None,
+            recorder: None,
+            synthetic_pressure: false,
+            stroke_start: None,
+            playback: None,
+        }
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    pub fn brush_size(&self) -> Property<i64> {
+        self.canvas.brush_size()
+    }
+
+    pub fn show_overlay(&self) -> Property<bool> {
+        self.canvas.show_overlay()
+    }
+
+    pub fn tool(&self) -> Property<String> {
+        self.canvas.tool()
+    }
+
+    pub fn layer_rows(&self) -> Vec<LayerRow> {
+        self.canvas.layer_rows()
+    }
+
+    pub fn layer_name(&self) -> Property<String> {
+        self.canvas.layer_name()
+    }
+
+    pub fn layer_blend(&self) -> Property<i64> {
+        self.canvas.layer_blend()
+    }
+
+    pub fn layer_opacity(&self) -> Property<i64> {
+        self.canvas.layer_opacity()
+    }
+
+    pub fn layer_clip(&self) -> Property<bool> {
+        self.canvas.layer_clip()
+    }
+
+    pub fn layer_masked(&self) -> Property<bool> {
+        self.canvas.layer_masked()
+    }
+
+    fn record(&mut self, step: &Step) {
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.record(step).is_err(),
+            None => false,
+        };
+        if failed {
+            println!("couldn't write the session log, recording stopped");
+            self.recorder = None;
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(why) = recorder.finish() {
+                println!("couldn't write the session log: {}", why);
+            }
+        }
+    }
+
+    fn play_due(&mut self) {
+        if let Some((mut player, start)) = self.playback.take() {
+            player.play_until(session::millis(start.elapsed()), self);
+            if !player.finished() {
+                self.playback = Some((player, start));
+            }
+        }
+    }
+
+    fn update_canvas(&mut self, message: &Message) {
+        self.canvas.update(message);
+        if let Some(score) = self.canvas.take_score() {
+            reactive::emit(Message::MatchScored(score));
+        }
+    }
+
+    fn redraw(&self) {
+        if let Some(ref area) = self.area {
+            area.queue_redraw_all();
+        }
+    }
+}
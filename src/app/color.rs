@@ -18,3 +18,40 @@ pub struct ColorRefMut<'a, T: 'static> {
     pub b: &'a mut T,
     pub a: &'a mut T,
 }
+
+// Storage of one channel of an image. Values read and write as 0..1; f32
+// keeps whatever it is given, which also fits signed data like normals.
+pub trait Channel: Copy + Clone + PartialEq + Default + 'static {
+    fn to_f64(self) -> f64;
+    fn from_f64(v: f64) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_f64(self) -> f64 {
+        self as f64 / 255.0
+    }
+
+    fn from_f64(v: f64) -> u8 {
+        (v.max(0.0).min(1.0) * 255.0).round() as u8
+    }
+}
+
+impl Channel for u16 {
+    fn to_f64(self) -> f64 {
+        self as f64 / 65535.0
+    }
+
+    fn from_f64(v: f64) -> u16 {
+        (v.max(0.0).min(1.0) * 65535.0).round() as u16
+    }
+}
+
+impl Channel for f32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(v: f64) -> f32 {
+        v as f32
+    }
+}
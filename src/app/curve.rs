@@ -0,0 +1,386 @@
+// Strokes as smooth curves.
+//
+// `BezierPath` is a chain of cubic Bézier segments fitted to sampled points
+// with Schneider's algorithm ("An Algorithm for Automatically Fitting
+// Digitized Curves", Graphics Gems, 1990): a segment is fitted by least
+// squares and split at the worst point until every sample is within the
+// tolerance. Arc length is measured with a per-segment lookup table, so the
+// path can be resampled at even distances.
+
+use app::vector::Vec2d;
+use app::stroke::StrokePoint;
+
+const LENGTH_STEPS: usize = 32; // per segment, for the arc length table
+const MAX_REPARAMETERIZE: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct CubicBezier {
+    pub p: [Vec2d; 4],
+}
+
+impl CubicBezier {
+    pub fn point(&self, t: f64) -> Vec2d {
+        let s = 1.0 - t;
+        self.p[0].smul(s * s * s) + self.p[1].smul(3.0 * s * s * t) +
+            self.p[2].smul(3.0 * s * t * t) + self.p[3].smul(t * t * t)
+    }
+
+    // First derivative.
+    pub fn tangent(&self, t: f64) -> Vec2d {
+        let s = 1.0 - t;
+        (self.p[1] - self.p[0]).smul(3.0 * s * s) + (self.p[2] - self.p[1]).smul(6.0 * s * t) +
+            (self.p[3] - self.p[2]).smul(3.0 * t * t)
+    }
+
+    fn second_derivative(&self, t: f64) -> Vec2d {
+        let s = 1.0 - t;
+        (self.p[2] - self.p[1].smul(2.0) + self.p[0]).smul(6.0 * s) +
+            (self.p[3] - self.p[2].smul(2.0) + self.p[1]).smul(6.0 * t)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BezierPath {
+    segments: Vec<CubicBezier>,
+    lengths: Vec<Vec<f64>>, // per segment, arc length at t = k / LENGTH_STEPS
+    total: f64,
+}
+
+// Cumulative distance along the points, scaled to end at 1.
+pub fn chord_lengths(points: &[Vec2d]) -> Vec<f64> {
+    let mut u = vec![0.0];
+    for i in 1..points.len() {
+        let d = u[i - 1] + (points[i] - points[i - 1]).len();
+        u.push(d);
+    }
+    let total = u[u.len() - 1];
+    if total > 0.0 {
+        for v in &mut u {
+            *v /= total;
+        }
+    }
+    u
+}
+
+// Least squares segment from points[0] to points[n-1] leaving along tan1
+// and arriving against tan2, with the points at parameters u.
+fn generate_bezier(points: &[Vec2d], u: &[f64], tan1: Vec2d, tan2: Vec2d) -> CubicBezier {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    let mut c = [[0.0; 2]; 2];
+    let mut x = [0.0; 2];
+    for (p, &t) in points.iter().zip(u.iter()) {
+        let s = 1.0 - t;
+        let a1 = tan1.smul(3.0 * s * s * t);
+        let a2 = tan2.smul(3.0 * s * t * t);
+        c[0][0] += a1.dot(a1);
+        c[0][1] += a1.dot(a2);
+        c[1][1] += a2.dot(a2);
+        let rest = *p - (first.smul(s * s * s + 3.0 * s * s * t) + last.smul(3.0 * s * t * t + t * t * t));
+        x[0] += a1.dot(rest);
+        x[1] += a2.dot(rest);
+    }
+    c[1][0] = c[0][1];
+    let det = c[0][0] * c[1][1] - c[1][0] * c[0][1];
+    let (mut alpha1, mut alpha2) = if det.abs() > 1e-12 {
+        ((x[0] * c[1][1] - x[1] * c[0][1]) / det, (c[0][0] * x[1] - c[1][0] * x[0]) / det)
+    } else {
+        (0.0, 0.0)
+    };
+    // degenerate fits fall back to handles a third of the way
+    let seg_len = (last - first).len();
+    let eps = 1e-6 * seg_len;
+    if alpha1 < eps || alpha2 < eps {
+        alpha1 = seg_len / 3.0;
+        alpha2 = seg_len / 3.0;
+    }
+    CubicBezier {
+        p: [first, first + tan1.smul(alpha1), last + tan2.smul(alpha2), last],
+    }
+}
+
+// Largest squared distance of a point from the segment, and where it is.
+fn max_error(points: &[Vec2d], u: &[f64], bezier: &CubicBezier) -> (f64, usize) {
+    let mut max = 0.0;
+    let mut split = points.len() / 2;
+    for i in 1..points.len() - 1 {
+        let d = (bezier.point(u[i]) - points[i]).norm();
+        if d >= max {
+            max = d;
+            split = i;
+        }
+    }
+    (max, split)
+}
+
+// One Newton-Raphson step towards the parameters closest to the points.
+fn reparameterize(points: &[Vec2d], u: &[f64], bezier: &CubicBezier) -> Vec<f64> {
+    points.iter().zip(u.iter()).map(|(p, &t)| {
+        let d = bezier.point(t) - *p;
+        let d1 = bezier.tangent(t);
+        let d2 = bezier.second_derivative(t);
+        let denominator = d1.dot(d1) + d.dot(d2);
+        if denominator.abs() < 1e-12 {
+            t
+        } else {
+            (t - d.dot(d1) / denominator).max(0.0).min(1.0)
+        }
+    }).collect()
+}
+
+fn fit_cubic(points: &[Vec2d], tan1: Vec2d, tan2: Vec2d, error: f64, segments: &mut Vec<CubicBezier>) {
+    if points.len() == 2 {
+        let dist = (points[1] - points[0]).len() / 3.0;
+        segments.push(CubicBezier {
+            p: [points[0], points[0] + tan1.smul(dist), points[1] + tan2.smul(dist), points[1]],
+        });
+        return;
+    }
+    let mut u = chord_lengths(points);
+    let mut bezier = generate_bezier(points, &u, tan1, tan2);
+    let (mut max, mut split) = max_error(points, &u, &bezier);
+    if max < error * error {
+        segments.push(bezier);
+        return;
+    }
+    if max < 4.0 * error * error {
+        for _ in 0..MAX_REPARAMETERIZE {
+            u = reparameterize(points, &u, &bezier);
+            bezier = generate_bezier(points, &u, tan1, tan2);
+            let e = max_error(points, &u, &bezier);
+            max = e.0;
+            split = e.1;
+            if max < error * error {
+                segments.push(bezier);
+                return;
+            }
+        }
+    }
+    let center = (points[split - 1] - points[split + 1]).normalize();
+    fit_cubic(&points[..split + 1], tan1, center, error, segments);
+    fit_cubic(&points[split..], center.smul(-1.0), tan2, error, segments);
+}
+
+impl BezierPath {
+    pub fn from_segments(segments: Vec<CubicBezier>) -> BezierPath {
+        let mut lengths = Vec::with_capacity(segments.len());
+        let mut total = 0.0;
+        for s in &segments {
+            let mut table = vec![0.0];
+            let mut prev = s.point(0.0);
+            for k in 1..LENGTH_STEPS + 1 {
+                let p = s.point(k as f64 / LENGTH_STEPS as f64);
+                let d = table[k - 1] + (p - prev).len();
+                table.push(d);
+                prev = p;
+            }
+            total += table[LENGTH_STEPS];
+            lengths.push(table);
+        }
+        BezierPath {
+            segments: segments,
+            lengths: lengths,
+            total: total,
+        }
+    }
+
+    // Fits the points so that none is farther than `tolerance` px from the path.
+    pub fn fit(points: &[Vec2d], tolerance: f64) -> BezierPath {
+        let mut clean: Vec<Vec2d> = vec![];
+        for p in points {
+            if clean.last().map_or(true, |q: &Vec2d| (*p - *q).norm() > 1e-12) {
+                clean.push(*p);
+            }
+        }
+        let mut segments = vec![];
+        if clean.len() == 1 {
+            segments.push(CubicBezier { p: [clean[0]; 4] });
+        } else if clean.len() > 1 {
+            let n = clean.len();
+            let tan1 = (clean[1] - clean[0]).normalize();
+            let tan2 = (clean[n - 2] - clean[n - 1]).normalize();
+            fit_cubic(&clean, tan1, tan2, tolerance.max(1e-3), &mut segments);
+        }
+        BezierPath::from_segments(segments)
+    }
+
+    pub fn length(&self) -> f64 {
+        self.total
+    }
+
+    // `t` runs from 0 to 1 over the whole path, each segment taking an equal share.
+    fn locate(&self, t: f64) -> (usize, f64) {
+        let n = self.segments.len();
+        let x = t.max(0.0).min(1.0) * n as f64;
+        let i = (x.floor() as usize).min(n - 1);
+        (i, x - i as f64)
+    }
+
+    pub fn point_at(&self, t: f64) -> Vec2d {
+        let (i, t) = self.locate(t);
+        self.segments[i].point(t)
+    }
+
+    pub fn tangent_at(&self, t: f64) -> Vec2d {
+        let (i, t) = self.locate(t);
+        self.segments[i].tangent(t).normalize()
+    }
+
+    // Path parameter at arc length `s` from the start.
+    pub fn t_at_length(&self, s: f64) -> f64 {
+        let n = self.segments.len();
+        if n == 0 || self.total <= 0.0 {
+            return 0.0;
+        }
+        let mut s = s.max(0.0).min(self.total);
+        for (i, table) in self.lengths.iter().enumerate() {
+            let len = table[LENGTH_STEPS];
+            if s > len && i + 1 < n {
+                s -= len;
+                continue;
+            }
+            let k = match table.iter().position(|&l| l >= s) {
+                Some(k) => k.max(1),
+                None => LENGTH_STEPS,
+            };
+            let span = table[k] - table[k - 1];
+            let f = if span > 0.0 { (s - table[k - 1]) / span } else { 0.0 };
+            let local = (k as f64 - 1.0 + f) / LENGTH_STEPS as f64;
+            return (i as f64 + local) / n as f64;
+        }
+        1.0
+    }
+
+    // Evenly spaced points at most `spacing` px apart along the path, both
+    // ends included.
+    pub fn resample(&self, spacing: f64) -> Vec<Vec2d> {
+        if self.segments.is_empty() {
+            return vec![];
+        }
+        let count = (self.total / spacing.max(1e-3)).ceil().max(1.0) as usize;
+        (0..count + 1).map(|k| self.point_at(self.t_at_length(self.total * k as f64 / count as f64))).collect()
+    }
+}
+
+// The samples moved onto their fitted curve, `spacing` px apart. Pressure,
+// tilt and time are carried over from the samples at the same fraction of
+// the length.
+pub fn resample_points(points: &[StrokePoint], tolerance: f64, spacing: f64) -> Vec<StrokePoint> {
+    let positions: Vec<Vec2d> = points.iter().map(|p| Vec2d::new(p.x, p.y)).collect();
+    resample_along(points, &BezierPath::fit(&positions, tolerance), spacing)
+}
+
+// Same with the curve already fitted to the samples.
+pub fn resample_along(points: &[StrokePoint], path: &BezierPath, spacing: f64) -> Vec<StrokePoint> {
+    if points.len() < 2 {
+        return points.to_vec();
+    }
+    let positions: Vec<Vec2d> = points.iter().map(|p| Vec2d::new(p.x, p.y)).collect();
+    let u = chord_lengths(&positions);
+    let samples = path.resample(spacing);
+    let last = samples.len().max(2) - 1;
+    let mut resampled = Vec::with_capacity(samples.len());
+    let mut j = 0;
+    for (k, p) in samples.iter().enumerate() {
+        let f = k as f64 / last as f64;
+        while j + 2 < u.len() && u[j + 1] < f {
+            j += 1;
+        }
+        let (a, b) = (&points[j], &points[j + 1]);
+        let span = u[j + 1] - u[j];
+        let t = if span > 0.0 { ((f - u[j]) / span).max(0.0).min(1.0) } else { 0.0 };
+        let lerp = |x: f64, y: f64| x + (y - x) * t;
+        resampled.push(StrokePoint {
+            x: p.x,
+            y: p.y,
+            pressure: lerp(a.pressure, b.pressure),
+            tilt_x: lerp(a.tilt_x, b.tilt_x),
+            tilt_y: lerp(a.tilt_y, b.tilt_y),
+            timestamp: lerp(a.timestamp as f64, b.timestamp as f64) as i64,
+            dragging: b.dragging,
+        });
+    }
+    resampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec2d, b: Vec2d, eps: f64) -> bool {
+        (a - b).len() < eps
+    }
+
+    // A straight segment with its handles at thirds runs at constant speed.
+    fn line(from: Vec2d, to: Vec2d) -> CubicBezier {
+        let d = (to - from).sdiv(3.0);
+        CubicBezier { p: [from, from + d, from + d.smul(2.0), to] }
+    }
+
+    // A wobbly line with some jitter on it.
+    fn samples() -> Vec<Vec2d> {
+        (0..200).map(|i| {
+            let x = i as f64 * 0.5;
+            let jitter = if i % 2 == 0 { 0.3 } else { -0.3 };
+            Vec2d::new(x, 10.0 * (x / 15.0).sin() + jitter)
+        }).collect()
+    }
+
+    #[test]
+    fn fit_stays_within_tolerance() {
+        let points = samples();
+        for &tolerance in [0.5, 2.0].iter() {
+            let path = BezierPath::fit(&points, tolerance);
+            let dense: Vec<Vec2d> = (0..4001).map(|k| path.point_at(k as f64 / 4000.0)).collect();
+            for p in &points {
+                let d = dense.iter().map(|q| (*q - *p).len()).fold(::std::f64::INFINITY, f64::min);
+                // the dense samples are a bit coarser than the curve
+                assert!(d <= tolerance + 0.05, "{} > {}", d, tolerance);
+            }
+            assert!(close(path.point_at(0.0), points[0], 1e-9));
+            assert!(close(path.point_at(1.0), points[points.len() - 1], 1e-9));
+        }
+        // a looser fit needs fewer segments
+        assert!(BezierPath::fit(&points, 2.0).segments.len() < BezierPath::fit(&points, 0.5).segments.len());
+    }
+
+    #[test]
+    fn resample_is_evenly_spaced() {
+        let path = BezierPath::fit(&samples(), 1.0);
+        let resampled = path.resample(3.0);
+        let gaps: Vec<f64> = resampled.windows(2).map(|w| (w[1] - w[0]).len()).collect();
+        let step = path.length() / gaps.len() as f64;
+        assert!(step <= 3.0);
+        // chords fall a little short of the arc between them
+        assert!(gaps.iter().all(|&g| (g - step).abs() < 0.05 * step), "{:?}", gaps);
+        assert!(close(resampled[0], path.point_at(0.0), 1e-9));
+        assert!(close(resampled[resampled.len() - 1], path.point_at(1.0), 1e-9));
+    }
+
+    #[test]
+    fn length_and_t_at_length() {
+        // 30 px then 10 px; each segment is half of t
+        let path = BezierPath::from_segments(vec![
+            line(Vec2d::new(0.0, 0.0), Vec2d::new(30.0, 0.0)),
+            line(Vec2d::new(30.0, 0.0), Vec2d::new(30.0, 10.0)),
+        ]);
+        assert!((path.length() - 40.0).abs() < 1e-9);
+        assert!((path.t_at_length(15.0) - 0.25).abs() < 1e-9);
+        assert!((path.t_at_length(30.0) - 0.5).abs() < 1e-9);
+        assert!((path.t_at_length(35.0) - 0.75).abs() < 1e-9);
+        assert_eq!(path.t_at_length(-1.0), 0.0);
+        assert_eq!(path.t_at_length(100.0), 1.0);
+        assert!(close(path.point_at(path.t_at_length(35.0)), Vec2d::new(30.0, 5.0), 1e-9));
+    }
+
+    #[test]
+    fn point_and_tangent_at() {
+        let bezier = CubicBezier { p: [Vec2d::new(0.0, 0.0), Vec2d::new(0.0, 10.0), Vec2d::new(10.0, 10.0), Vec2d::new(10.0, 0.0)] };
+        let path = BezierPath::from_segments(vec![bezier]);
+        assert!(close(path.point_at(0.5), Vec2d::new(5.0, 7.5), 1e-9));
+        assert!(close(path.tangent_at(0.0), Vec2d::new(0.0, 1.0), 1e-9));
+        assert!(close(path.tangent_at(0.5), Vec2d::new(1.0, 0.0), 1e-9));
+        assert!(close(path.tangent_at(1.0), Vec2d::new(0.0, -1.0), 1e-9));
+        // t is clamped to the path
+        assert!(close(path.point_at(2.0), Vec2d::new(10.0, 0.0), 1e-9));
+    }
+}
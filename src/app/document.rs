@@ -0,0 +1,708 @@
+// Native sketch file (.skb).
+//
+// The file starts with the magic "SKBK" and a u32 format version, followed by
+// chunks of the form [4-byte tag][u32 payload length][payload]. All numbers
+// are little endian. Readers skip chunks they do not know, so new chunk types
+// can be added without bumping the version.
+//
+//   HEAD  width u32, height u32, active layer u32
+//   TIPS  stamp count u32, then each stamp bitmap once (see write_stamp)
+//   LAYR  one per layer, bottom to top (see write_layer)
+//   END   empty, marks the end of the document
+
+use std::io;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use app::canvas::{CanvasImage, LayerImage, Layer, BlendMode};
+use app::brush::{Brush, BrushTip, BrushBlend};
+use app::dynamics::{Dynamic, Input, Curve};
+use app::tip::{Calligraphic, Stamp};
+use app::color::Color;
+use app::stroke::{Stroke, StrokePoint};
+
+const MAGIC: &'static [u8; 4] = b"SKBK";
+pub const VERSION: u32 = 1;
+const MAX_SIDE: u32 = 1 << 14; // px, for images read from a file
+
+pub struct Document {
+    pub width: u32,
+    pub height: u32,
+    pub active_layer: usize,
+    pub layers: Vec<Layer>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// width * height, rejecting sizes no canvas or stamp can have.
+fn pixel_count(width: u32, height: u32) -> io::Result<usize> {
+    if width > MAX_SIDE || height > MAX_SIDE {
+        return Err(invalid("image too large"));
+    }
+    (width as usize).checked_mul(height as usize).ok_or_else(|| invalid("image too large"))
+}
+
+fn put_u8(buff: &mut Vec<u8>, v: u8) {
+    buff.push(v);
+}
+
+fn put_u16(buff: &mut Vec<u8>, v: u16) {
+    buff.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buff: &mut Vec<u8>, v: u32) {
+    buff.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_f32(buff: &mut Vec<u8>, v: f32) {
+    buff.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_i64(buff: &mut Vec<u8>, v: i64) {
+    buff.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_f64(buff: &mut Vec<u8>, v: f64) {
+    buff.extend_from_slice(&v.to_le_bytes());
+}
+
+// byte length u32, then UTF-8
+fn put_str(buff: &mut Vec<u8>, v: &str) {
+    put_u32(buff, v.len() as u32);
+    buff.extend_from_slice(v.as_bytes());
+}
+
+// Cursor over a chunk payload.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(invalid("unexpected end of chunk"));
+        }
+        let s = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    // A count of items at least `item_size` bytes each. Counts the rest of
+    // the chunk cannot hold are rejected before anything is allocated.
+    fn count(&mut self, item_size: usize) -> io::Result<usize> {
+        let n = self.u32()? as usize;
+        if n.saturating_mul(item_size) > self.data.len() - self.pos {
+            return Err(invalid("unexpected end of chunk"));
+        }
+        Ok(n)
+    }
+
+    fn u16s(&mut self, n: usize) -> io::Result<Vec<u16>> {
+        let size = n.checked_mul(2).ok_or_else(|| invalid("unexpected end of chunk"))?;
+        Ok(self.take(size)?.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect())
+    }
+
+    fn f32s(&mut self, n: usize) -> io::Result<Vec<f32>> {
+        let size = n.checked_mul(4).ok_or_else(|| invalid("unexpected end of chunk"))?;
+        Ok(self.take(size)?.chunks(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(b))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(b))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+}
+
+fn blend_mode_to_u8(mode: &BlendMode) -> u8 {
+    match mode {
+        &BlendMode::Normal => 0,
+        &BlendMode::Multiply => 1,
+        &BlendMode::Screen => 2,
+        &BlendMode::Overlay => 3,
+        &BlendMode::Darken => 4,
+        &BlendMode::Lighten => 5,
+        &BlendMode::ColorDodge => 6,
+        &BlendMode::ColorBurn => 7,
+        &BlendMode::HardLight => 8,
+        &BlendMode::SoftLight => 9,
+        &BlendMode::Difference => 10,
+        &BlendMode::Exclusion => 11,
+        &BlendMode::Add => 12,
+        &BlendMode::Subtract => 13,
+        &BlendMode::Hue => 14,
+        &BlendMode::Saturation => 15,
+        &BlendMode::Color => 16,
+        &BlendMode::Luminosity => 17,
+    }
+}
+
+fn blend_mode_from_u8(v: u8) -> io::Result<BlendMode> {
+    match v {
+        0 => Ok(BlendMode::Normal),
+        1 => Ok(BlendMode::Multiply),
+        2 => Ok(BlendMode::Screen),
+        3 => Ok(BlendMode::Overlay),
+        4 => Ok(BlendMode::Darken),
+        5 => Ok(BlendMode::Lighten),
+        6 => Ok(BlendMode::ColorDodge),
+        7 => Ok(BlendMode::ColorBurn),
+        8 => Ok(BlendMode::HardLight),
+        9 => Ok(BlendMode::SoftLight),
+        10 => Ok(BlendMode::Difference),
+        11 => Ok(BlendMode::Exclusion),
+        12 => Ok(BlendMode::Add),
+        13 => Ok(BlendMode::Subtract),
+        14 => Ok(BlendMode::Hue),
+        15 => Ok(BlendMode::Saturation),
+        16 => Ok(BlendMode::Color),
+        17 => Ok(BlendMode::Luminosity),
+        _ => Err(invalid("unknown blend mode")),
+    }
+}
+
+// width u32, height u32, coverage u8 per pixel
+fn write_stamp(buff: &mut Vec<u8>, stamp: &Stamp) {
+    put_u32(buff, stamp.width() as u32);
+    put_u32(buff, stamp.height() as u32);
+    for c in stamp.coverage_data() {
+        put_u8(buff, (c * 255.0).round() as u8);
+    }
+}
+
+fn read_stamp(r: &mut Reader) -> io::Result<Stamp> {
+    let width = r.u32()?;
+    let height = r.u32()?;
+    let n = pixel_count(width, height)?;
+    let coverage = r.take(n)?.iter().map(|&c| c as f64 / 255.0).collect();
+    Ok(Stamp::new(width as usize, height as usize, coverage))
+}
+
+// The distinct stamps the strokes of `layers` are drawn with.
+fn stamp_table(layers: &Vec<Layer>) -> Vec<Rc<Stamp>> {
+    let mut stamps: Vec<Rc<Stamp>> = vec![];
+    for s in layers.iter().flat_map(|l| l.strokes()) {
+        if let BrushTip::Stamp(ref stamp) = s.brush.tip {
+            if !stamps.iter().any(|t| Rc::ptr_eq(t, stamp) || t == stamp) {
+                stamps.push(stamp.clone());
+            }
+        }
+    }
+    stamps
+}
+
+// kind u8, then for calligraphic aspect f64, angle f64 and for stamps the
+// index u32 into the stamp table
+fn write_tip(buff: &mut Vec<u8>, tip: &BrushTip, stamps: &[Rc<Stamp>]) {
+    match tip {
+        &BrushTip::Contour => put_u8(buff, 0),
+        &BrushTip::RoundHard => put_u8(buff, 1),
+        &BrushTip::RoundSoft => put_u8(buff, 2),
+        &BrushTip::Square => put_u8(buff, 3),
+        &BrushTip::Calligraphic(ref c) => {
+            put_u8(buff, 4);
+            put_f64(buff, c.aspect);
+            put_f64(buff, c.angle);
+        },
+        &BrushTip::Stamp(ref s) => {
+            put_u8(buff, 5);
+            let index = stamps.iter().position(|t| Rc::ptr_eq(t, s) || t == s).unwrap();
+            put_u32(buff, index as u32);
+        },
+    }
+}
+
+fn read_tip(r: &mut Reader, stamps: &[Rc<Stamp>]) -> io::Result<BrushTip> {
+    match r.u8()? {
+        0 => Ok(BrushTip::Contour),
+        1 => Ok(BrushTip::RoundHard),
+        2 => Ok(BrushTip::RoundSoft),
+        3 => Ok(BrushTip::Square),
+        4 => Ok(BrushTip::Calligraphic(Calligraphic {
+            aspect: r.f64()?,
+            angle: r.f64()?,
+        })),
+        5 => match stamps.get(r.u32()? as usize) {
+            Some(stamp) => Ok(BrushTip::Stamp(stamp.clone())),
+            None => Err(invalid("unknown stamp")),
+        },
+        _ => Err(invalid("unknown brush tip")),
+    }
+}
+
+fn brush_blend_to_u8(blend: BrushBlend) -> u8 {
+    match blend {
+        BrushBlend::Normal => 0,
+        BrushBlend::Multiply => 1,
+        BrushBlend::Screen => 2,
+        BrushBlend::Behind => 3,
+        BrushBlend::LockAlpha => 4,
+        BrushBlend::Erase => 5,
+    }
+}
+
+fn brush_blend_from_u8(v: u8) -> io::Result<BrushBlend> {
+    match v {
+        0 => Ok(BrushBlend::Normal),
+        1 => Ok(BrushBlend::Multiply),
+        2 => Ok(BrushBlend::Screen),
+        3 => Ok(BrushBlend::Behind),
+        4 => Ok(BrushBlend::LockAlpha),
+        5 => Ok(BrushBlend::Erase),
+        _ => Err(invalid("unknown brush blend mode")),
+    }
+}
+
+fn input_to_u8(input: Input) -> u8 {
+    match input {
+        Input::None => 0,
+        Input::Pressure => 1,
+        Input::Tilt => 2,
+        Input::Speed => 3,
+    }
+}
+
+fn input_from_u8(v: u8) -> io::Result<Input> {
+    match v {
+        0 => Ok(Input::None),
+        1 => Ok(Input::Pressure),
+        2 => Ok(Input::Tilt),
+        3 => Ok(Input::Speed),
+        _ => Err(invalid("unknown dynamics input")),
+    }
+}
+
+// input u8, curve point count u32, points as (x f64, y f64)
+fn write_dynamic(buff: &mut Vec<u8>, dynamic: &Dynamic) {
+    put_u8(buff, input_to_u8(dynamic.input));
+    put_u32(buff, dynamic.curve.points.len() as u32);
+    for &(x, y) in &dynamic.curve.points {
+        put_f64(buff, x);
+        put_f64(buff, y);
+    }
+}
+
+fn read_dynamic(r: &mut Reader) -> io::Result<Dynamic> {
+    let input = input_from_u8(r.u8()?)?;
+    let num_points = r.count(16)?;
+    let mut points = vec![];
+    for _ in 0..num_points {
+        points.push((r.f64()?, r.f64()?));
+    }
+    Ok(Dynamic::new(input, Curve::new(points)))
+}
+
+fn write_brush(buff: &mut Vec<u8>, brush: &Brush, stamps: &[Rc<Stamp>]) {
+    write_tip(buff, &brush.tip, stamps);
+    put_f64(buff, brush.size);
+    put_f64(buff, brush.color.r);
+    put_f64(buff, brush.color.g);
+    put_f64(buff, brush.color.b);
+    put_f64(buff, brush.color.a);
+    put_f64(buff, brush.opacity);
+    put_f64(buff, brush.spacing);
+    for d in &[&brush.dynamics.size, &brush.dynamics.opacity, &brush.dynamics.flow] {
+        write_dynamic(buff, d);
+    }
+    put_u8(buff, brush_blend_to_u8(brush.blend));
+    put_u8(buff, brush.eraser as u8);
+}
+
+fn read_brush(r: &mut Reader, stamps: &[Rc<Stamp>]) -> io::Result<Brush> {
+    let mut brush = Brush::new();
+    brush.tip = read_tip(r, stamps)?;
+    brush.size = r.f64()?;
+    brush.color = Color::new(r.f64()?, r.f64()?, r.f64()?, r.f64()?);
+    brush.opacity = r.f64()?;
+    brush.spacing = r.f64()?;
+    brush.dynamics.size = read_dynamic(r)?;
+    brush.dynamics.opacity = read_dynamic(r)?;
+    brush.dynamics.flow = read_dynamic(r)?;
+    brush.blend = brush_blend_from_u8(r.u8()?)?;
+    brush.eraser = r.u8()? != 0;
+    Ok(brush)
+}
+
+fn write_stroke(buff: &mut Vec<u8>, stroke: &Stroke, stamps: &[Rc<Stamp>]) {
+    put_u8(buff, stroke.finished as u8);
+    write_brush(buff, &stroke.brush, stamps);
+    put_u32(buff, stroke.points.len() as u32);
+    for p in &stroke.points {
+        put_f64(buff, p.x);
+        put_f64(buff, p.y);
+        put_f64(buff, p.pressure);
+        put_f64(buff, p.tilt_x);
+        put_f64(buff, p.tilt_y);
+        put_i64(buff, p.timestamp);
+        put_u8(buff, p.dragging as u8);
+    }
+}
+
+fn read_stroke(r: &mut Reader, stamps: &[Rc<Stamp>]) -> io::Result<Stroke> {
+    let finished = r.u8()? != 0;
+    let brush = read_brush(r, stamps)?;
+    let num_points = r.count(57)?;
+    let mut stroke = Stroke::new(num_points, brush);
+    stroke.finished = finished;
+    for _ in 0..num_points {
+        stroke.push(StrokePoint {
+            x: r.f64()?,
+            y: r.f64()?,
+            pressure: r.f64()?,
+            tilt_x: r.f64()?,
+            tilt_y: r.f64()?,
+            timestamp: r.i64()?,
+            dragging: r.u8()? != 0,
+        });
+    }
+    Ok(stroke)
+}
+
+// id u32, name, visible u8, blend mode u8, width u32, height u32,
+// premultiplied RGBA data as u16, stroke count u32, strokes, opacity f64,
+// clip u8, has mask u8, then the mask as one gray u16 per pixel, has normals
+// u8, then the normal field as x, y, z, w f32 per pixel
+fn write_layer(buff: &mut Vec<u8>, layer: &Layer, stamps: &[Rc<Stamp>]) {
+    put_u32(buff, layer.id());
+    put_str(buff, layer.name());
+    put_u8(buff, layer.visible() as u8);
+    put_u8(buff, blend_mode_to_u8(layer.blend_mode()));
+    put_u32(buff, layer.image().width());
+    put_u32(buff, layer.image().height());
+    for &v in layer.image().data() {
+        put_u16(buff, v);
+    }
+    put_u32(buff, layer.strokes().len() as u32);
+    for s in layer.strokes() {
+        write_stroke(buff, s, stamps);
+    }
+    put_f64(buff, layer.opacity());
+    put_u8(buff, layer.clip() as u8);
+    put_u8(buff, layer.mask().is_some() as u8);
+    if let Some(mask) = layer.mask() {
+        for p in mask.data().chunks(4) {
+            put_u16(buff, p[0]);
+        }
+    }
+    put_u8(buff, layer.normals().is_some() as u8);
+    if let Some(normals) = layer.normals() {
+        for &v in normals.data() {
+            put_f32(buff, v);
+        }
+    }
+}
+
+fn read_layer(r: &mut Reader, stamps: &[Rc<Stamp>]) -> io::Result<Layer> {
+    let id = r.u32()?;
+    let name = r.string()?;
+    let visible = r.u8()? != 0;
+    let blend_mode = blend_mode_from_u8(r.u8()?)?;
+    let width = r.u32()?;
+    let height = r.u32()?;
+    let image = LayerImage::from_data(width, height, r.u16s(pixel_count(width, height)? * 4)?);
+    let num_strokes = r.count(1)?;
+    let mut strokes = Vec::with_capacity(num_strokes);
+    for _ in 0..num_strokes {
+        strokes.push(read_stroke(r, stamps)?);
+    }
+    let mut layer = Layer::from_parts(id, name, visible, blend_mode, image, strokes);
+    layer.set_opacity(r.f64()?);
+    layer.set_clip(r.u8()? != 0);
+    if r.u8()? != 0 {
+        let gray = r.u16s(pixel_count(width, height)?)?;
+        let data = gray.iter().flat_map(|&g| vec![g, g, g, 65535]).collect();
+        *layer.mask_mut() = Some(LayerImage::from_data(width, height, data));
+    }
+    if r.u8()? != 0 {
+        let n = pixel_count(width, height)? * 4;
+        *layer.normals_mut() = Some(CanvasImage::from_data(width, height, r.f32s(n)?));
+    }
+    Ok(layer)
+}
+
+fn write_chunk<W: Write>(w: &mut W, tag: &[u8; 4], payload: &[u8]) -> io::Result<()> {
+    w.write_all(tag)?;
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)
+}
+
+pub fn save<W: Write>(w: &mut W, width: u32, height: u32, active_layer: usize, layers: &Vec<Layer>) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+
+    let mut head = vec![];
+    put_u32(&mut head, width);
+    put_u32(&mut head, height);
+    put_u32(&mut head, active_layer as u32);
+    write_chunk(w, b"HEAD", &head)?;
+
+    let stamps = stamp_table(layers);
+    let mut tips = vec![];
+    put_u32(&mut tips, stamps.len() as u32);
+    for s in &stamps {
+        write_stamp(&mut tips, s);
+    }
+    write_chunk(w, b"TIPS", &tips)?;
+
+    for l in layers {
+        let mut buff = vec![];
+        write_layer(&mut buff, l, &stamps);
+        write_chunk(w, b"LAYR", &buff)?;
+    }
+    write_chunk(w, b"END ", &[])?;
+    w.flush()
+}
+
+pub fn load<R: Read>(r: &mut R) -> io::Result<Document> {
+    let mut header = [0; 8];
+    r.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC {
+        return Err(invalid("not a sketch book document"));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..8]);
+    let version = u32::from_le_bytes(version);
+    if version > VERSION {
+        return Err(invalid("document was written by a newer version"));
+    }
+
+    let mut doc: Option<Document> = None;
+    let mut stamps = vec![]; // shared by every stroke that uses them
+    loop {
+        let mut chunk_header = [0; 8];
+        r.read_exact(&mut chunk_header)?;
+        let mut len = [0; 4];
+        len.copy_from_slice(&chunk_header[4..8]);
+        let len = u32::from_le_bytes(len) as u64;
+        // grows with what is actually there instead of trusting the length
+        let mut payload = vec![];
+        r.by_ref().take(len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < len {
+            return Err(invalid("unexpected end of file"));
+        }
+        let mut reader = Reader::new(&payload);
+        match &chunk_header[0..4] {
+            b"HEAD" => {
+                let width = reader.u32()?;
+                let height = reader.u32()?;
+                if width == 0 || height == 0 {
+                    return Err(invalid("empty canvas"));
+                }
+                pixel_count(width, height)?;
+                doc = Some(Document {
+                    width: width,
+                    height: height,
+                    active_layer: reader.u32()? as usize,
+                    layers: vec![],
+                });
+            },
+            b"TIPS" => {
+                let n = reader.count(8)?;
+                stamps = vec![];
+                for _ in 0..n {
+                    stamps.push(Rc::new(read_stamp(&mut reader)?));
+                }
+            },
+            b"LAYR" => match doc {
+                Some(ref mut doc) => {
+                    let layer = read_layer(&mut reader, &stamps)?;
+                    if layer.image().width() != doc.width || layer.image().height() != doc.height {
+                        return Err(invalid("layer size does not match the document"));
+                    }
+                    doc.layers.push(layer);
+                },
+                None => return Err(invalid("layer before header")),
+            },
+            b"END " => break,
+            _ => (),
+        }
+    }
+
+    match doc {
+        Some(doc) => {
+            if doc.active_layer >= doc.layers.len() {
+                return Err(invalid("active layer out of range"));
+            }
+            Ok(doc)
+        },
+        None => Err(invalid("missing header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use app::stroke::Stroke;
+
+    fn sample() -> Vec<u8> {
+        let mut layer = Layer::new(1, "Background", 3, 2, 255);
+        let mut stroke = Stroke::new(0, Brush::new());
+        stroke.push(StrokePoint { x: 1.0, y: 1.0, pressure: 0.5, tilt_x: 0.0, tilt_y: 0.0, timestamp: 3, dragging: true });
+        layer.strokes_mut().push(stroke);
+        let mut normals = CanvasImage::<f32>::new(3, 2, 0);
+        normals.set_pixel(2, 1, [-0.6, 0.0, 0.8, 1.0]);
+        *layer.normals_mut() = Some(normals);
+        let mut buff = vec![];
+        save(&mut buff, 3, 2, 0, &vec![layer]).unwrap();
+        buff
+    }
+
+    fn chunk(tag: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut buff = vec![];
+        write_chunk(&mut buff, tag, payload).unwrap();
+        buff
+    }
+
+    fn document(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut buff = MAGIC.to_vec();
+        put_u32(&mut buff, VERSION);
+        for c in chunks {
+            buff.extend_from_slice(c);
+        }
+        buff
+    }
+
+    fn head(width: u32, height: u32) -> Vec<u8> {
+        let mut head = vec![];
+        put_u32(&mut head, width);
+        put_u32(&mut head, height);
+        put_u32(&mut head, 0);
+        chunk(b"HEAD", &head)
+    }
+
+    #[test]
+    fn round_trip() {
+        let doc = load(&mut &sample()[..]).unwrap();
+        assert_eq!((doc.width, doc.height, doc.active_layer), (3, 2, 0));
+        assert_eq!(doc.layers[0].name(), "Background");
+        assert_eq!(doc.layers[0].strokes()[0].points[0].timestamp, 3);
+        let normals = doc.layers[0].normals().unwrap();
+        assert_eq!(normals.pixel(2, 1), [-0.6, 0.0, 0.8, 1.0]);
+        assert_eq!(normals.pixel(0, 0), [0.0; 4]);
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let buff = sample();
+        for n in 0..buff.len() {
+            assert!(load(&mut &buff[..n]).is_err(), "loaded the first {} bytes", n);
+        }
+    }
+
+    #[test]
+    fn chunk_longer_than_the_file_is_an_error() {
+        let mut buff = document(&[head(3, 2)]);
+        buff.extend_from_slice(b"LAYR");
+        put_u32(&mut buff, u32::max_value());
+        buff.extend_from_slice(&[0; 16]);
+        assert!(load(&mut &buff[..]).is_err());
+    }
+
+    #[test]
+    fn oversized_images_are_errors() {
+        assert!(load(&mut &document(&[head(65536, 65536), chunk(b"END ", &[])])[..]).is_err());
+        assert!(load(&mut &document(&[head(0, 4), chunk(b"END ", &[])])[..]).is_err());
+
+        let mut layer = vec![];
+        put_u32(&mut layer, 1);
+        put_str(&mut layer, "big");
+        put_u8(&mut layer, 1);
+        put_u8(&mut layer, 0);
+        put_u32(&mut layer, 65536);
+        put_u32(&mut layer, 65536);
+        let buff = document(&[head(3, 2), chunk(b"LAYR", &layer), chunk(b"END ", &[])]);
+        assert!(load(&mut &buff[..]).is_err());
+    }
+
+    #[test]
+    fn counts_beyond_the_chunk_are_errors() {
+        let mut layer = vec![];
+        put_u32(&mut layer, 1);
+        put_str(&mut layer, "l");
+        put_u8(&mut layer, 1);
+        put_u8(&mut layer, 0);
+        put_u32(&mut layer, 1);
+        put_u32(&mut layer, 1);
+        layer.extend_from_slice(&[0; 8]);
+        put_u32(&mut layer, u32::max_value()); // strokes
+        let buff = document(&[head(1, 1), chunk(b"LAYR", &layer), chunk(b"END ", &[])]);
+        assert!(load(&mut &buff[..]).is_err());
+    }
+
+    #[test]
+    fn oversized_stamp_is_an_error() {
+        let mut tips = vec![];
+        put_u32(&mut tips, 1);
+        put_u32(&mut tips, 1 << 20);
+        put_u32(&mut tips, 1 << 20);
+        tips.extend_from_slice(&[0; 64]);
+        let buff = document(&[head(1, 1), chunk(b"TIPS", &tips), chunk(b"END ", &[])]);
+        assert!(load(&mut &buff[..]).is_err());
+    }
+
+    fn stamped(count: usize) -> Vec<u8> {
+        let stamp = Rc::new(Stamp::new(32, 32, vec![0.5; 32 * 32]));
+        let mut layer = Layer::new(1, "Background", 3, 2, 255);
+        for i in 0..count {
+            let mut brush = Brush::new();
+            // equal bitmaps in separate stamps count as one
+            brush.tip = BrushTip::Stamp(if i == 1 { Rc::new(Stamp::new(32, 32, vec![0.5; 32 * 32])) } else { stamp.clone() });
+            layer.strokes_mut().push(Stroke::new(0, brush));
+        }
+        let mut buff = vec![];
+        save(&mut buff, 3, 2, 0, &vec![layer]).unwrap();
+        buff
+    }
+
+    #[test]
+    fn stamps_are_written_once_and_shared() {
+        // two more strokes cost much less than a bitmap
+        assert!(stamped(3).len() < stamped(1).len() + 32 * 32);
+        let doc = load(&mut &stamped(3)[..]).unwrap();
+        let tips: Vec<_> = doc.layers[0].strokes().iter().map(|s| match s.brush.tip {
+            BrushTip::Stamp(ref stamp) => stamp.clone(),
+            _ => panic!("not a stamp"),
+        }).collect();
+        assert_eq!(tips[0].coverage_data()[0], 128.0 / 255.0);
+        assert!(Rc::ptr_eq(&tips[0], &tips[1]) && Rc::ptr_eq(&tips[0], &tips[2]));
+    }
+
+    #[test]
+    fn unknown_stamp_is_an_error() {
+        let mut tip = vec![];
+        put_u8(&mut tip, 5);
+        put_u32(&mut tip, 0);
+        assert!(read_tip(&mut Reader::new(&tip), &[]).is_err());
+    }
+}
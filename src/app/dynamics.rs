@@ -0,0 +1,250 @@
+// Brush dynamics: pen input driving the size, opacity and flow of each dab.
+//
+// Every dab samples the pen at its position along the stroke (pressure and
+// tilt are interpolated between points, speed comes from the timestamps) and
+// passes the chosen input through a curve. The result scales the brush value.
+// When the device reports no pressure, as with a mouse, pressure is derived
+// from speed instead: the faster the stroke, the lighter.
+
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+use app::brush::{Brush, BrushBlend};
+use app::canvas::saturate;
+use app::stroke::StrokePoint;
+
+const MAX_SPEED: f64 = 2.0; // px/ms, counts as full speed
+const SYNTHETIC_PERIOD: f64 = 1000.0; // ms
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Input {
+    None,
+    Pressure,
+    Tilt,
+    Speed,
+}
+
+// Piecewise linear map from 0..1 to 0..1 through points sorted by x.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Curve {
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Curve {
+    pub fn new(mut points: Vec<(f64, f64)>) -> Curve {
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        Curve {
+            points: points,
+        }
+    }
+
+    // from `min` at no input up to 1 at full input
+    pub fn linear(min: f64) -> Curve {
+        Curve::new(vec![(0.0, min), (1.0, 1.0)])
+    }
+
+    pub fn eval(&self, x: f64) -> f64 {
+        let first = match self.points.first() {
+            Some(p) => p,
+            None => return 1.0,
+        };
+        if x <= first.0 {
+            return first.1;
+        }
+        for w in self.points.windows(2) {
+            let (p0, p1) = (w[0], w[1]);
+            if x <= p1.0 {
+                if p1.0 - p0.0 <= 0.0 {
+                    return p1.1;
+                }
+                return p0.1 + (p1.1 - p0.1) * (x - p0.0) / (p1.0 - p0.0);
+            }
+        }
+        self.points[self.points.len() - 1].1
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Dynamic {
+    pub input: Input,
+    pub curve: Curve,
+}
+
+impl Dynamic {
+    pub fn none() -> Dynamic {
+        Dynamic {
+            input: Input::None,
+            curve: Curve::linear(1.0),
+        }
+    }
+
+    pub fn new(input: Input, curve: Curve) -> Dynamic {
+        Dynamic {
+            input: input,
+            curve: curve,
+        }
+    }
+
+    pub fn factor(&self, sample: &Sample) -> f64 {
+        let x = match self.input {
+            Input::None => return 1.0,
+            Input::Pressure => sample.pressure,
+            Input::Tilt => sample.tilt,
+            Input::Speed => sample.speed,
+        };
+        saturate(self.curve.eval(saturate(x, 0.0, 1.0)), 0.0, 1.0)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Dynamics {
+    pub size: Dynamic,
+    pub opacity: Dynamic, // scales the stroke's opacity cap
+    pub flow: Dynamic,    // scales the alpha of each dab
+}
+
+impl Dynamics {
+    pub fn new() -> Dynamics {
+        Dynamics {
+            size: Dynamic::none(),
+            opacity: Dynamic::none(),
+            flow: Dynamic::none(),
+        }
+    }
+}
+
+// Pen state at a dab, every value in 0..1.
+pub struct Sample {
+    pub pressure: f64,
+    pub tilt: f64,
+    pub speed: f64,
+}
+
+impl Sample {
+    // At `t` (0..1) along the segment p0-p1.
+    pub fn between(p0: &StrokePoint, p1: &StrokePoint, t: f64) -> Sample {
+        let dt = (p1.timestamp - p0.timestamp).max(1) as f64;
+        let len = ((p1.x - p0.x).powi(2) + (p1.y - p0.y).powi(2)).sqrt();
+        let speed = saturate(len / dt / MAX_SPEED, 0.0, 1.0);
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        let pressure = if p0.pressure > 0.0 || p1.pressure > 0.0 {
+            lerp(p0.pressure, p1.pressure)
+        } else {
+            1.0 - speed
+        };
+        let tilt = (lerp(p0.tilt_x, p1.tilt_x).powi(2) + lerp(p0.tilt_y, p1.tilt_y).powi(2)).sqrt();
+        Sample {
+            pressure: saturate(pressure, 0.0, 1.0),
+            tilt: saturate(tilt, 0.0, 1.0),
+            speed: speed,
+        }
+    }
+}
+
+// What a single dab is drawn with.
+pub struct Dab {
+    pub size: f64,
+    pub flow: f64,
+    pub opacity: f64,
+    pub blend: BrushBlend,
+}
+
+impl Dab {
+    pub fn new(brush: &Brush, sample: &Sample) -> Dab {
+        let d = &brush.dynamics;
+        Dab {
+            size: brush.size * d.size.factor(sample),
+            flow: brush.color.a * d.flow.factor(sample),
+            opacity: brush.opacity * d.opacity.factor(sample),
+            blend: brush.dab_blend(),
+        }
+    }
+}
+
+// Pressure for testing without a tablet: swells from 0.1 to 1 and back once
+// a second, counted from the start of the stroke. It never reaches 0, which
+// would read as a device without pressure.
+pub fn synthetic_pressure(elapsed_ms: i64) -> f64 {
+    0.55 - 0.45 * (2.0 * PI * elapsed_ms as f64 / SYNTHETIC_PERIOD).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f64, pressure: f64, timestamp: i64) -> StrokePoint {
+        StrokePoint { x: x, y: 0.0, pressure: pressure, tilt_x: 0.0, tilt_y: 0.0, timestamp: timestamp, dragging: true }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn curve_interpolates_and_clamps() {
+        // given out of order
+        let curve = Curve::new(vec![(1.0, 1.0), (0.0, 0.2), (0.5, 0.4)]);
+        assert_eq!(curve.points[0], (0.0, 0.2));
+        assert!(close(curve.eval(0.25), 0.3));
+        assert!(close(curve.eval(0.75), 0.7));
+        assert!(close(curve.eval(-1.0), 0.2));
+        assert!(close(curve.eval(2.0), 1.0));
+        assert!(close(Curve::linear(0.5).eval(0.5), 0.75));
+        assert!(close(Curve::new(vec![]).eval(0.3), 1.0));
+    }
+
+    #[test]
+    fn factor_maps_the_chosen_input() {
+        let sample = Sample { pressure: 0.5, tilt: 0.25, speed: 1.0 };
+        assert!(close(Dynamic::none().factor(&sample), 1.0));
+        assert!(close(Dynamic::new(Input::Pressure, Curve::linear(0.0)).factor(&sample), 0.5));
+        assert!(close(Dynamic::new(Input::Tilt, Curve::linear(0.0)).factor(&sample), 0.25));
+        // an inverted curve: faster is thinner
+        let speed = Dynamic::new(Input::Speed, Curve::new(vec![(0.0, 1.0), (1.0, 0.1)]));
+        assert!(close(speed.factor(&sample), 0.1));
+        // curves reaching beyond 0..1 are cut
+        let steep = Dynamic::new(Input::Pressure, Curve::new(vec![(0.0, -1.0), (1.0, 3.0)]));
+        assert!(close(steep.factor(&sample), 1.0));
+        assert!(close(steep.factor(&Sample { pressure: 0.0, tilt: 0.0, speed: 0.0 }), 0.0));
+    }
+
+    #[test]
+    fn device_pressure_is_interpolated() {
+        let s = Sample::between(&point(0.0, 0.2, 0), &point(1.0, 0.6, 10), 0.25);
+        assert!(close(s.pressure, 0.3));
+        assert!(close(s.speed, 0.1 / MAX_SPEED));
+    }
+
+    #[test]
+    fn speed_stands_in_for_missing_pressure() {
+        // 1 px in 10 ms is slow and heavy, 10 px in 5 ms is full speed
+        let slow = Sample::between(&point(0.0, 0.0, 0), &point(1.0, 0.0, 10), 0.5);
+        let fast = Sample::between(&point(0.0, 0.0, 0), &point(10.0, 0.0, 5), 0.5);
+        assert!(close(slow.pressure, 1.0 - 0.1 / MAX_SPEED));
+        assert!(close(fast.speed, 1.0));
+        assert!(close(fast.pressure, 0.0));
+        // points at the same time count as 1 ms apart
+        let jump = Sample::between(&point(0.0, 0.0, 7), &point(1.0, 0.0, 7), 0.0);
+        assert!(close(jump.speed, 1.0 / MAX_SPEED));
+    }
+
+    #[test]
+    fn dab_scales_the_brush() {
+        let mut brush = Brush::new();
+        brush.size = 10.0;
+        brush.opacity = 0.8;
+        brush.dynamics.size = Dynamic::new(Input::Pressure, Curve::linear(0.0));
+        brush.dynamics.flow = Dynamic::new(Input::Pressure, Curve::linear(0.5));
+        let dab = Dab::new(&brush, &Sample { pressure: 0.5, tilt: 0.0, speed: 0.0 });
+        assert!(close(dab.size, 5.0));
+        assert!(close(dab.flow, 0.75));
+        assert!(close(dab.opacity, 0.8));
+    }
+
+    #[test]
+    fn synthetic_pressure_stays_positive() {
+        assert!(close(synthetic_pressure(0), 0.1));
+        assert!(close(synthetic_pressure(500), 1.0));
+        assert!((0..2000).all(|ms| synthetic_pressure(ms) >= 0.1 - 1e-12));
+    }
+}
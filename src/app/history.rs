@@ -0,0 +1,374 @@
+use std::mem;
+
+use app::canvas::{LayerImage, LayerChannel, Layer, BlendMode, Rect, saturate, layer_index};
+use app::brush::Brush;
+use app::stroke::Stroke;
+
+pub const TILE_SIZE: u32 = 64;
+const DEFAULT_LIMIT: usize = 100;
+
+struct Tile {
+    tx: u32,
+    ty: u32,
+    data: Vec<LayerChannel>,
+}
+
+fn tile_rect(image: &LayerImage, tx: u32, ty: u32) -> Rect<i32> {
+    Rect::new((tx * TILE_SIZE) as i32,
+              (ty * TILE_SIZE) as i32,
+              ((tx + 1) * TILE_SIZE).min(image.width()) as i32,
+              ((ty + 1) * TILE_SIZE).min(image.height()) as i32)
+}
+
+fn tiles_in(image: &LayerImage, rect: &Rect<i32>) -> Vec<(u32, u32)> {
+    let lt_x = saturate(rect.lt_x, 0, image.width() as i32) as u32;
+    let lt_y = saturate(rect.lt_y, 0, image.height() as i32) as u32;
+    let rb_x = saturate(rect.rb_x, 0, image.width() as i32) as u32;
+    let rb_y = saturate(rect.rb_y, 0, image.height() as i32) as u32;
+    let mut tiles = vec![];
+    if lt_x >= rb_x || lt_y >= rb_y {
+        return tiles;
+    }
+    for ty in (lt_y / TILE_SIZE)..((rb_y - 1) / TILE_SIZE + 1) {
+        for tx in (lt_x / TILE_SIZE)..((rb_x - 1) / TILE_SIZE + 1) {
+            tiles.push((tx, ty));
+        }
+    }
+    tiles
+}
+
+// Pixels of the tiles an edit touched. Undoing and redoing both just swap
+// them with the current layer image, so only one copy is kept per tile.
+pub struct TileSnapshot {
+    tiles: Vec<Tile>,
+}
+
+impl TileSnapshot {
+    pub fn new() -> TileSnapshot {
+        TileSnapshot {
+            tiles: vec![],
+        }
+    }
+
+    // Snapshot of the tiles of `old` that will change when it is replaced by `new`.
+    pub fn diff(old: &LayerImage, new: &LayerImage) -> TileSnapshot {
+        let mut snapshot = TileSnapshot::new();
+        let whole = Rect::new(0, 0, old.width() as i32, old.height() as i32);
+        for (tx, ty) in tiles_in(old, &whole) {
+            let rect = tile_rect(old, tx, ty);
+            let data = old.region(&rect);
+            if data != new.region(&rect) {
+                snapshot.tiles.push(Tile { tx: tx, ty: ty, data: data });
+            }
+        }
+        snapshot
+    }
+
+    // Saves the tiles covering `rect` unless they were saved before, so the
+    // first (untouched) state of each tile is the one that is kept.
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    pub fn capture(&mut self, image: &LayerImage, rect: &Rect<i32>) {
+        for (tx, ty) in tiles_in(image, rect) {
+            if !self.tiles.iter().any(|t| t.tx == tx && t.ty == ty) {
+                let data = image.region(&tile_rect(image, tx, ty));
+                self.tiles.push(Tile { tx: tx, ty: ty, data: data });
+            }
+        }
+    }
+
+    pub fn swap(&mut self, image: &mut LayerImage) -> Option<Rect<i32>> {
+        let mut rect: Option<Rect<i32>> = None;
+        for t in &mut self.tiles {
+            let r = tile_rect(image, t.tx, t.ty);
+            let current = image.region(&r);
+            image.set_region(&r, &t.data);
+            t.data = current;
+            if let Some(ref mut r0) = rect {
+                r0.merge(r);
+                continue;
+            }
+            rect = Some(r);
+        }
+        rect
+    }
+}
+
+fn merge_rect(rect: &mut Option<Rect<i32>>, other: Option<Rect<i32>>) {
+    if let Some(r1) = other {
+        if let Some(ref mut r0) = *rect {
+            r0.merge(r1);
+            return;
+        }
+        *rect = Some(r1);
+    }
+}
+
+// The whole canvas, for changes to the layer stack.
+fn whole(layers: &Vec<Layer>) -> Option<Rect<i32>> {
+    layers.first().map(|l| Rect::new(0, 0, l.image().width() as i32, l.image().height() as i32))
+}
+
+// Layers are referred to by id and stack positions are counted from the
+// bottom. Commands that add or remove a layer hold it while it is out of
+// the stack, so undoing and redoing both just move it in or out.
+pub enum Command {
+    AddStroke { layer: u32, stroke: Stroke, tiles: TileSnapshot },
+    Clear { layers: Vec<(u32, Vec<Stroke>, TileSnapshot)> },
+    ChangeBrush { brush: Brush },
+    SwapLayer { index: usize, layer: Option<Layer> }, // added or deleted
+    MoveLayer { from: usize, to: usize },
+    RenameLayer { layer: u32, name: String },
+    LayerBlend { layer: u32, mode: BlendMode },
+    LayerOpacity { layer: u32, opacity: f64 },
+    LayerClip { layer: u32 }, // toggled
+    LayerMask { layer: u32, mask: Option<LayerImage> },
+    MergeDown { index: usize, upper: Option<Layer>, strokes: Vec<Stroke>, tiles: TileSnapshot,
+                opacity: f64, mask: Option<LayerImage> }, // of the lower layer
+    Flatten { layers: Vec<Layer> },
+}
+
+impl Command {
+    // Returns the canvas area that has to be recomposited.
+    fn apply(&mut self, layers: &mut Vec<Layer>, brush: &mut Brush, undo: bool) -> Option<Rect<i32>> {
+        match self {
+            &mut Command::AddStroke { layer, ref stroke, ref mut tiles } => {
+                let i = layer_index(layers, layer)?;
+                if undo {
+                    layers[i].strokes_mut().pop();
+                } else {
+                    layers[i].strokes_mut().push(stroke.clone());
+                }
+                tiles.swap(layers[i].image_mut())
+            },
+            &mut Command::Clear { layers: ref mut cleared } => {
+                let mut rect: Option<Rect<i32>> = None;
+                for &mut (id, ref mut strokes, ref mut tiles) in cleared.iter_mut() {
+                    if let Some(i) = layer_index(layers, id) {
+                        mem::swap(layers[i].strokes_mut(), strokes);
+                        merge_rect(&mut rect, tiles.swap(layers[i].image_mut()));
+                    }
+                }
+                rect
+            },
+            &mut Command::ChangeBrush { brush: ref mut other } => {
+                mem::swap(brush, other);
+                None
+            },
+            &mut Command::SwapLayer { index, ref mut layer } => {
+                match layer.take() {
+                    Some(l) => layers.insert(index, l),
+                    None => *layer = Some(layers.remove(index)),
+                }
+                whole(layers)
+            },
+            &mut Command::MoveLayer { from, to } => {
+                let (from, to) = if undo { (to, from) } else { (from, to) };
+                let l = layers.remove(from);
+                layers.insert(to, l);
+                whole(layers)
+            },
+            &mut Command::RenameLayer { layer, ref mut name } => {
+                let i = layer_index(layers, layer)?;
+                let current = layers[i].name().to_string();
+                layers[i].set_name(mem::replace(name, current));
+                None
+            },
+            &mut Command::LayerBlend { layer, ref mut mode } => {
+                let i = layer_index(layers, layer)?;
+                let current = *layers[i].blend_mode();
+                layers[i].set_blend_mode(mem::replace(mode, current));
+                whole(layers)
+            },
+            &mut Command::LayerOpacity { layer, ref mut opacity } => {
+                let i = layer_index(layers, layer)?;
+                let current = layers[i].opacity();
+                layers[i].set_opacity(mem::replace(opacity, current));
+                whole(layers)
+            },
+            &mut Command::LayerClip { layer } => {
+                let i = layer_index(layers, layer)?;
+                let clip = !layers[i].clip();
+                layers[i].set_clip(clip);
+                whole(layers)
+            },
+            &mut Command::LayerMask { layer, ref mut mask } => {
+                let i = layer_index(layers, layer)?;
+                mem::swap(layers[i].mask_mut(), mask);
+                whole(layers)
+            },
+            &mut Command::MergeDown { index, ref mut upper, ref mut strokes, ref mut tiles, ref mut opacity, ref mut mask } => {
+                match upper.take() {
+                    Some(l) => layers.insert(index, l),
+                    None => *upper = Some(layers.remove(index)),
+                }
+                let lower = &mut layers[index - 1];
+                mem::swap(lower.strokes_mut(), strokes);
+                tiles.swap(lower.image_mut());
+                let old = lower.opacity();
+                lower.set_opacity(*opacity);
+                *opacity = old;
+                mem::swap(lower.mask_mut(), mask);
+                whole(layers)
+            },
+            &mut Command::Flatten { layers: ref mut other } => {
+                mem::swap(layers, other);
+                whole(layers)
+            },
+        }
+    }
+}
+
+// Edits that come as a run of messages, one per slider tick or keystroke.
+// A run of pushes with the same burst undoes as one step.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Burst {
+    BrushSize,
+    LayerName(u32),
+    LayerOpacity(u32),
+}
+
+// Each step also keeps the id of a layer to select once it is applied: the
+// layer that was active before the step on the undo stack and the one that
+// was active when it was undone on the redo stack.
+pub struct History {
+    undo_stack: Vec<(Command, u32)>,
+    redo_stack: Vec<(Command, u32)>,
+    limit: usize,
+    active: u32,
+    burst: Option<Burst>, // of the command on top of the undo stack, while the run goes on
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            undo_stack: vec![],
+            redo_stack: vec![],
+            limit: DEFAULT_LIMIT,
+            active: 0,
+            burst: None,
+        }
+    }
+
+    // After undo or redo, the layer to select.
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    // Called by the owner whenever the selection may have changed.
+    pub fn set_active(&mut self, id: u32) {
+        self.active = id;
+    }
+
+    pub fn push(&mut self, command: Command) {
+        self.burst = None;
+        self.redo_stack.clear();
+        self.undo_stack.push((command, self.active));
+        if self.undo_stack.len() > self.limit {
+            self.undo_stack.remove(0);
+        }
+    }
+
+    // Pushes `command` unless it continues the run on top of the stack, in
+    // which case the state from before the first edit of the run is kept.
+    pub fn push_burst(&mut self, command: Command, burst: Burst) {
+        if self.burst == Some(burst) {
+            self.redo_stack.clear();
+            return;
+        }
+        self.push(command);
+        self.burst = Some(burst);
+    }
+
+    // The next edit starts a new undo step, even if it continues the burst.
+    pub fn end_burst(&mut self) {
+        self.burst = None;
+    }
+
+    pub fn undo(&mut self, layers: &mut Vec<Layer>, brush: &mut Brush) -> Option<Rect<i32>> {
+        self.burst = None;
+        match self.undo_stack.pop() {
+            Some((mut command, mut active)) => {
+                let rect = command.apply(layers, brush, true);
+                mem::swap(&mut self.active, &mut active);
+                self.redo_stack.push((command, active));
+                rect
+            },
+            None => None,
+        }
+    }
+
+    pub fn redo(&mut self, layers: &mut Vec<Layer>, brush: &mut Brush) -> Option<Rect<i32>> {
+        self.burst = None;
+        match self.redo_stack.pop() {
+            Some((mut command, mut active)) => {
+                let rect = command.apply(layers, brush, false);
+                mem::swap(&mut self.active, &mut active);
+                self.undo_stack.push((command, active));
+                rect
+            },
+            None => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.burst = None;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change_size(history: &mut History, brush: &mut Brush, size: f64, burst: Option<Burst>) {
+        let command = Command::ChangeBrush { brush: brush.clone() };
+        match burst {
+            Some(burst) => history.push_burst(command, burst),
+            None => history.push(command),
+        }
+        brush.size = size;
+    }
+
+    #[test]
+    fn one_slider_drag_undoes_as_one_step() {
+        let (mut history, mut brush, mut layers) = (History::new(), Brush::new(), vec![]);
+        brush.size = 2.0;
+        for &size in [3.0, 4.0, 5.0].iter() {
+            change_size(&mut history, &mut brush, size, Some(Burst::BrushSize));
+        }
+        history.undo(&mut layers, &mut brush);
+        assert_eq!(brush.size, 2.0);
+        assert!(history.undo_stack.is_empty());
+    }
+
+    #[test]
+    fn separate_edits_stay_separate() {
+        let (mut history, mut brush, mut layers) = (History::new(), Brush::new(), vec![]);
+        brush.size = 2.0;
+        change_size(&mut history, &mut brush, 3.0, Some(Burst::BrushSize));
+        change_size(&mut history, &mut brush, 4.0, None);
+        // a new drag after something else happened
+        change_size(&mut history, &mut brush, 5.0, Some(Burst::BrushSize));
+        history.end_burst();
+        change_size(&mut history, &mut brush, 6.0, Some(Burst::BrushSize));
+        for &expected in [5.0, 4.0, 3.0, 2.0].iter() {
+            history.undo(&mut layers, &mut brush);
+            assert_eq!(brush.size, expected);
+        }
+    }
+
+    #[test]
+    fn bursts_of_different_layers_do_not_merge() {
+        let (mut history, mut brush, mut layers) = (History::new(), Brush::new(), vec![]);
+        history.push_burst(Command::ChangeBrush { brush: brush.clone() }, Burst::LayerOpacity(1));
+        history.push_burst(Command::ChangeBrush { brush: brush.clone() }, Burst::LayerOpacity(2));
+        assert_eq!(history.undo_stack.len(), 2);
+        history.undo(&mut layers, &mut brush);
+        history.push_burst(Command::ChangeBrush { brush: brush.clone() }, Burst::LayerOpacity(1));
+        assert_eq!(history.undo_stack.len(), 2);
+    }
+}
@@ -0,0 +1,266 @@
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::fs::File;
+use std::path::Path;
+
+use png;
+
+use app::canvas::CanvasImage;
+use app::color::Channel;
+
+const MAX_SIDE: u32 = 1 << 14; // px, for headers read from a file
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn write_png<W: Write, T: Channel>(w: W, image: &CanvasImage<T>) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(w, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.straight_data())?;
+    writer.finish()?;
+    Ok(())
+}
+
+// Any PNG flavour is accepted; palette, grayscale and 16 bit images are
+// converted to 8 bit RGBA.
+pub fn read_png<R: BufRead + Seek>(r: R) -> io::Result<CanvasImage> {
+    let mut decoder = png::Decoder::new(r);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let size = match reader.output_buffer_size() {
+        Some(size) => size,
+        None => return Err(invalid("image too large")),
+    };
+    let mut buff = vec![0; size];
+    let info = reader.next_frame(&mut buff)?;
+    let num_pixels = (info.width * info.height) as usize;
+    let mut data = Vec::with_capacity(num_pixels * 4);
+    match info.color_type {
+        png::ColorType::Rgba => data.extend_from_slice(&buff[..num_pixels * 4]),
+        png::ColorType::Rgb => for p in buff.chunks(3).take(num_pixels) {
+            data.extend_from_slice(&[p[0], p[1], p[2], 255]);
+        },
+        png::ColorType::GrayscaleAlpha => for p in buff.chunks(2).take(num_pixels) {
+            data.extend_from_slice(&[p[0], p[0], p[0], p[1]]);
+        },
+        png::ColorType::Grayscale => for p in buff.iter().take(num_pixels) {
+            data.extend_from_slice(&[*p, *p, *p, 255]);
+        },
+        png::ColorType::Indexed => {
+            return Err(invalid("unexpanded palette image"));
+        },
+    }
+    Ok(CanvasImage::from_straight(info.width, info.height, data))
+}
+
+// Binary PPM (P6). Alpha is dropped.
+pub fn write_ppm<W: Write, T: Channel>(mut w: W, image: &CanvasImage<T>) -> io::Result<()> {
+    write!(w, "P6\n{} {}\n255\n", image.width(), image.height())?;
+    let mut row = Vec::with_capacity(image.width() as usize * 3);
+    for line in image.straight_data().chunks(image.width() as usize * 4) {
+        row.clear();
+        for p in line.chunks(4) {
+            row.extend_from_slice(&p[0..3]);
+        }
+        w.write_all(&row)?;
+    }
+    w.flush()
+}
+
+// PAM (P7) with TUPLTYPE RGB_ALPHA, which keeps the alpha channel.
+pub fn write_pam<W: Write, T: Channel>(mut w: W, image: &CanvasImage<T>) -> io::Result<()> {
+    write!(w, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+           image.width(), image.height())?;
+    for line in image.straight_data().chunks(image.width() as usize * 4) {
+        w.write_all(line)?;
+    }
+    w.flush()
+}
+
+// Next whitespace separated header token of a PNM file. Comments are skipped.
+fn read_token<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut token = String::new();
+    let mut byte = [0; 1];
+    loop {
+        r.read_exact(&mut byte)?;
+        match byte[0] {
+            b'#' => {
+                let mut comment = vec![];
+                r.read_until(b'\n', &mut comment)?;
+                if !token.is_empty() {
+                    return Ok(token);
+                }
+            },
+            b' ' | b'\t' | b'\n' | b'\r' => if !token.is_empty() {
+                return Ok(token);
+            },
+            c => token.push(c as char),
+        }
+    }
+}
+
+fn parse_u32(token: &str) -> io::Result<u32> {
+    token.parse().map_err(|_| invalid("malformed number in header"))
+}
+
+// Reads `depth` channels of width * height samples and expands them to RGBA.
+fn read_raster<R: Read>(r: &mut R, width: u32, height: u32, depth: u32, maxval: u32) -> io::Result<CanvasImage> {
+    if depth < 1 || depth > 4 || maxval < 1 || maxval > 65535 {
+        return Err(invalid("unsupported image format"));
+    }
+    let sample_size = if maxval > 255 { 2 } else { 1 };
+    let too_large = || invalid("image too large");
+    if width > MAX_SIDE || height > MAX_SIDE {
+        return Err(too_large());
+    }
+    let row_size = (width as usize).checked_mul((depth * sample_size) as usize).ok_or_else(too_large)?;
+    let size = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4)).ok_or_else(too_large)?;
+    let mut row = vec![0; row_size];
+    // grown row by row, so a header promising more than the file has costs nothing
+    let mut data = Vec::with_capacity(size.min(1 << 24));
+    let mut px = [0u8; 4];
+    for _ in 0..height {
+        r.read_exact(&mut row)?;
+        for sample in row.chunks(sample_size as usize * depth as usize) {
+            for c in 0..depth as usize {
+                let v = if sample_size == 2 {
+                    (sample[c * 2] as u32) << 8 | sample[c * 2 + 1] as u32
+                } else {
+                    sample[c] as u32
+                };
+                px[c] = ((v * 255 + maxval / 2) / maxval) as u8;
+            }
+            match depth {
+                1 => data.extend_from_slice(&[px[0], px[0], px[0], 255]),
+                2 => data.extend_from_slice(&[px[0], px[0], px[0], px[1]]),
+                3 => data.extend_from_slice(&[px[0], px[1], px[2], 255]),
+                _ => data.extend_from_slice(&px),
+            }
+        }
+    }
+    Ok(CanvasImage::from_straight(width, height, data))
+}
+
+// Reads binary PGM (P5), PPM (P6) and PAM (P7) images.
+pub fn read_pnm<R: BufRead>(mut r: R) -> io::Result<CanvasImage> {
+    let magic = read_token(&mut r)?;
+    match magic.as_str() {
+        "P5" | "P6" => {
+            let width = parse_u32(&read_token(&mut r)?)?;
+            let height = parse_u32(&read_token(&mut r)?)?;
+            let maxval = parse_u32(&read_token(&mut r)?)?;
+            let depth = if magic == "P5" { 1 } else { 3 };
+            read_raster(&mut r, width, height, depth, maxval)
+        },
+        "P7" => {
+            let (mut width, mut height, mut depth, mut maxval) = (0, 0, 0, 0);
+            loop {
+                let key = read_token(&mut r)?;
+                match key.as_str() {
+                    "WIDTH" => width = parse_u32(&read_token(&mut r)?)?,
+                    "HEIGHT" => height = parse_u32(&read_token(&mut r)?)?,
+                    "DEPTH" => depth = parse_u32(&read_token(&mut r)?)?,
+                    "MAXVAL" => maxval = parse_u32(&read_token(&mut r)?)?,
+                    "TUPLTYPE" => {
+                        read_token(&mut r)?;
+                    },
+                    "ENDHDR" => break,
+                    _ => return Err(invalid("unknown PAM header field")),
+                }
+            }
+            read_raster(&mut r, width, height, depth, maxval)
+        },
+        _ => Err(invalid("not a binary PNM image")),
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase()
+}
+
+// Writes PNG, or PPM / PAM when the file name asks for it.
+pub fn save<T: Channel>(path: &Path, image: &CanvasImage<T>) -> io::Result<()> {
+    let w = BufWriter::new(File::create(path)?);
+    match extension(path).as_str() {
+        "ppm" => write_ppm(w, image),
+        "pam" => write_pam(w, image),
+        _ => write_png(w, image),
+    }
+}
+
+pub fn open(path: &Path) -> io::Result<CanvasImage> {
+    let r = BufReader::new(File::open(path)?);
+    match extension(path).as_str() {
+        "pgm" | "ppm" | "pam" => read_pnm(r),
+        _ => read_png(r),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Straight RGBA that survives premultiplying: opaque pixels and a
+    // transparent one.
+    fn sample() -> CanvasImage {
+        CanvasImage::from_straight(3, 2, vec![
+            255, 0, 0, 255,   0, 255, 0, 255,   0, 0, 255, 255,
+            10, 20, 30, 255,  200, 100, 50, 255,  0, 0, 0, 0,
+        ])
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let mut buff = vec![];
+        write_ppm(&mut buff, &sample()).unwrap();
+        let image = read_pnm(Cursor::new(buff)).unwrap();
+        assert_eq!((image.width(), image.height()), (3, 2));
+        // alpha is dropped, the transparent pixel comes back opaque black
+        let mut expected = sample().straight_data();
+        expected[23] = 255;
+        assert_eq!(image.straight_data(), expected);
+    }
+
+    #[test]
+    fn pam_round_trip() {
+        let mut buff = vec![];
+        write_pam(&mut buff, &sample()).unwrap();
+        let image = read_pnm(Cursor::new(buff)).unwrap();
+        assert_eq!(image.straight_data(), sample().straight_data());
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut buff = vec![];
+        write_png(&mut buff, &sample()).unwrap();
+        let image = read_png(Cursor::new(buff)).unwrap();
+        assert_eq!(image.straight_data(), sample().straight_data());
+    }
+
+    #[test]
+    fn sixteen_bit_maxval_is_scaled() {
+        let mut buff = b"P5\n2 1\n65535\n".to_vec();
+        buff.extend_from_slice(&[0xff, 0xff, 0x80, 0x00]);
+        let image = read_pnm(Cursor::new(buff)).unwrap();
+        assert_eq!(image.straight_data(), vec![255, 255, 255, 255, 128, 128, 128, 255]);
+        let mut buff = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 2\nMAXVAL 1000\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n".to_vec();
+        buff.extend_from_slice(&[0x03, 0xe8, 0x03, 0xe8]);
+        let image = read_pnm(Cursor::new(buff)).unwrap();
+        assert_eq!(image.straight_data(), vec![255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn crafted_headers_are_errors() {
+        let huge = b"P6\n4294967295 4294967295\n255\n".to_vec();
+        assert!(read_pnm(Cursor::new(huge)).is_err());
+        let pam = b"P7\nWIDTH 100000\nHEIGHT 3\nDEPTH 4\nMAXVAL 65535\nENDHDR\n".to_vec();
+        assert!(read_pnm(Cursor::new(pam)).is_err());
+        // header fine, pixels missing
+        let short = b"P6\n2 2\n255\n\x01\x02\x03".to_vec();
+        assert!(read_pnm(Cursor::new(short)).is_err());
+    }
+}
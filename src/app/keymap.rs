@@ -0,0 +1,216 @@
+// Keyboard shortcuts.
+//
+// A keymap file has one binding per line, `<chord> = <action>`, for example
+//
+//     ctrl+z = undo
+//     ctrl+shift+z = redo
+//     ] = brush_size_up
+//
+// A chord is a character key with any of the ctrl, alt, shift and super
+// (or cmd) modifiers. The `+` and `=` keys separate the parts of a line, so
+// they are written `plus` and `equal`, and the space bar is `space`. Blank
+// lines and lines starting with '#' are ignored. Bindings in the file
+// override the defaults; a bad line is reported and skipped.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use message::Message;
+use reactive::widget::AreaKeyEvent;
+
+pub const KEYMAP_FILE: &'static str = "keymap.conf";
+
+// same bits as libui's uiModifiers
+pub const MODIFIER_CTRL: u8 = 1 << 0;
+pub const MODIFIER_ALT: u8 = 1 << 1;
+pub const MODIFIER_SHIFT: u8 = 1 << 2;
+pub const MODIFIER_SUPER: u8 = 1 << 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyChord {
+    pub key: u8,
+    pub modifiers: u8,
+}
+
+impl KeyChord {
+    pub fn new(key: u8, modifiers: u8) -> KeyChord {
+        KeyChord {
+            key: key.to_ascii_lowercase(),
+            modifiers: modifiers,
+        }
+    }
+
+    pub fn from_event(e: &AreaKeyEvent) -> KeyChord {
+        KeyChord::new(e.key, e.modifiers.bits())
+    }
+
+    pub fn parse(spec: &str) -> Result<KeyChord, String> {
+        let mut modifiers = 0;
+        let mut key = None;
+        for part in spec.split('+').map(|p| p.trim()) {
+            match part.to_lowercase().as_str() {
+                "ctrl" => modifiers |= MODIFIER_CTRL,
+                "alt" => modifiers |= MODIFIER_ALT,
+                "shift" => modifiers |= MODIFIER_SHIFT,
+                "super" | "cmd" => modifiers |= MODIFIER_SUPER,
+                "space" if key.is_none() => key = Some(b' '),
+                "plus" if key.is_none() => key = Some(b'+'),
+                "equal" if key.is_none() => key = Some(b'='),
+                p if p.len() == 1 && key.is_none() => key = Some(p.as_bytes()[0]),
+                _ => return Err(format!("bad key chord: {}", spec)),
+            }
+        }
+        match key {
+            Some(key) => Ok(KeyChord::new(key, modifiers)),
+            None => Err(format!("no key in chord: {}", spec)),
+        }
+    }
+}
+
+fn parse_action(name: &str) -> Result<Message, String> {
+    match name {
+        "undo" => Ok(Message::Undo),
+        "redo" => Ok(Message::Redo),
+        "clear" => Ok(Message::ClearCanvasButton),
+        "save" => Ok(Message::Save),
+        "close_stroke" => Ok(Message::StrokeCloseButton),
+        "score" => Ok(Message::OutputButton),
+        "brush_toggle" => Ok(Message::BrushToggleButton),
+        "brush_size_up" => Ok(Message::BrushSizeStep(1)),
+        "brush_size_down" => Ok(Message::BrushSizeStep(-1)),
+        "new_layer" => Ok(Message::AddLayer),
+        "duplicate_layer" => Ok(Message::DuplicateLayer),
+        "delete_layer" => Ok(Message::DeleteLayer),
+        "merge_down" => Ok(Message::MergeLayerDown),
+        "layer_above" => Ok(Message::SelectLayerStep(1)),
+        "layer_below" => Ok(Message::SelectLayerStep(-1)),
+        _ => Err(format!("unknown action: {}", name)),
+    }
+}
+
+fn parse_binding(line: &str) -> Result<(KeyChord, Message), String> {
+    let mut parts = line.splitn(2, '=');
+    let chord = parts.next().unwrap_or("");
+    let action = match parts.next() {
+        Some(action) => action.trim(),
+        None => return Err("expected `<chord> = <action>`".to_string()),
+    };
+    Ok((KeyChord::parse(chord)?, parse_action(action)?))
+}
+
+pub struct Keymap {
+    bindings: Vec<(KeyChord, Message)>,
+}
+
+impl Keymap {
+    pub fn new() -> Keymap {
+        let mut keymap = Keymap {
+            bindings: vec![],
+        };
+        let defaults = [
+            ("ctrl+z", Message::Undo),
+            ("ctrl+shift+z", Message::Redo),
+            ("ctrl+y", Message::Redo),
+            ("ctrl+shift+c", Message::ClearCanvasButton),
+            ("ctrl+s", Message::Save),
+            ("[", Message::BrushSizeStep(-1)),
+            ("]", Message::BrushSizeStep(1)),
+            ("ctrl+shift+n", Message::AddLayer),
+            ("ctrl+e", Message::MergeLayerDown),
+        ];
+        for &(chord, ref message) in defaults.iter() {
+            keymap.bind(KeyChord::parse(chord).unwrap(), message.clone());
+        }
+        keymap
+    }
+
+    // The defaults with the bindings of `path` applied, if the file exists.
+    pub fn load(path: &Path) -> Keymap {
+        let mut keymap = Keymap::new();
+        let mut text = String::new();
+        if let Ok(mut file) = File::open(path) {
+            if let Err(why) = file.read_to_string(&mut text) {
+                println!("couldn't read {}: {}", path.display(), why);
+            }
+        }
+        for why in keymap.apply(&text) {
+            println!("{}: {}", path.display(), why);
+        }
+        keymap
+    }
+
+    // Binds every good line of `text` and returns what was wrong with the others.
+    pub fn apply(&mut self, text: &str) -> Vec<String> {
+        let mut errors = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_binding(line) {
+                Ok((chord, message)) => self.bind(chord, message),
+                Err(why) => errors.push(format!("line {}: {}", n + 1, why)),
+            }
+        }
+        errors
+    }
+
+    pub fn bind(&mut self, chord: KeyChord, message: Message) {
+        self.bindings.retain(|&(c, _)| c != chord);
+        self.bindings.push((chord, message));
+    }
+
+    pub fn lookup(&self, chord: KeyChord) -> Option<&Message> {
+        self.bindings.iter().find(|&&(c, _)| c == chord).map(|&(_, ref m)| m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages have no PartialEq, their debug text tells them apart.
+    fn action(keymap: &Keymap, chord: &str) -> Option<String> {
+        keymap.lookup(KeyChord::parse(chord).unwrap()).map(|m| format!("{:?}", m))
+    }
+
+    #[test]
+    fn chords_parse_modifiers_and_fold_case() {
+        let chord = KeyChord::parse("Ctrl + Shift+Z").unwrap();
+        assert_eq!(chord, KeyChord::new(b'z', MODIFIER_CTRL | MODIFIER_SHIFT));
+        assert_eq!(KeyChord::parse("cmd+alt+s").unwrap(), KeyChord::new(b'S', MODIFIER_SUPER | MODIFIER_ALT));
+        assert_eq!(KeyChord::parse("super+space").unwrap(), KeyChord::new(b' ', MODIFIER_SUPER));
+        assert_eq!(KeyChord::parse("ctrl+plus").unwrap(), KeyChord::new(b'+', MODIFIER_CTRL));
+        assert_eq!(KeyChord::parse("equal").unwrap(), KeyChord::new(b'=', 0));
+        assert!(KeyChord::parse("ctrl+shift").is_err());
+        assert!(KeyChord::parse("ctrl+zz").is_err());
+        assert!(KeyChord::parse("a+b").is_err());
+        assert!(KeyChord::parse("ctrl++").is_err());
+    }
+
+    #[test]
+    fn the_file_overrides_the_defaults() {
+        let mut keymap = Keymap::new();
+        assert_eq!(action(&keymap, "ctrl+z"), Some("Undo".to_string()));
+        let errors = keymap.apply("# mine\n\nctrl+z = redo\nplus = brush_size_up\n");
+        assert!(errors.is_empty());
+        assert_eq!(action(&keymap, "ctrl+z"), Some("Redo".to_string()));
+        assert_eq!(action(&keymap, "plus"), Some("BrushSizeStep(1)".to_string()));
+        // the other defaults stay
+        assert_eq!(action(&keymap, "ctrl+s"), Some("Save".to_string()));
+        assert_eq!(action(&keymap, "ctrl+q"), None);
+    }
+
+    #[test]
+    fn bad_lines_are_reported_and_skipped() {
+        let mut keymap = Keymap::new();
+        let errors = keymap.apply("ctrl+q = quit\nctrl+shift = undo\nctrl+w\nctrl+d = duplicate_layer\n");
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("line 1: unknown action"));
+        assert!(errors[1].starts_with("line 2: no key"));
+        assert!(errors[2].starts_with("line 3: expected"));
+        assert_eq!(action(&keymap, "ctrl+q"), None);
+        assert_eq!(action(&keymap, "ctrl+d"), Some("DuplicateLayer".to_string()));
+    }
+}
@@ -1,8 +1,23 @@
 use message::Message;
 
 pub mod canvas;
+pub mod canvas_model;
+pub mod render;
+pub mod session;
 pub mod brush;
+pub mod blend;
+pub mod stamp;
+pub mod tip;
+pub mod dynamics;
+pub mod smoothing;
+pub mod curve;
 pub mod stroke;
 pub mod vector;
 pub mod config;
 pub mod color;
+pub mod history;
+pub mod document;
+pub mod image_io;
+pub mod scoring;
+pub mod score;
+pub mod keymap;
@@ -0,0 +1,93 @@
+// The stroke -> layer -> composite pipeline seen from outside.
+//
+// `Canvas` implements `Renderer` without any window, so a scripted session
+// can be rendered headless, for example
+//
+//     let image = render::render_headless(640.0, 480.0, &steps);
+//
+// `CanvasModel` implements it too and redraws its area as steps arrive.
+
+use message::Message;
+
+use app::canvas::{Canvas, CanvasImage};
+use app::stroke::StrokePoint;
+
+pub trait Renderer {
+    fn send(&mut self, message: &Message);
+    fn stroke(&mut self, point: StrokePoint);
+    fn frame(&self) -> CanvasImage;
+}
+
+#[derive(Clone)]
+pub enum Step {
+    Message(Message),
+    Point(StrokePoint),
+}
+
+impl Renderer for Canvas {
+    fn send(&mut self, message: &Message) {
+        self.update(message);
+    }
+
+    fn stroke(&mut self, point: StrokePoint) {
+        self.mouse_event(point);
+    }
+
+    fn frame(&self) -> CanvasImage {
+        self.frame_image()
+    }
+}
+
+pub fn replay<R: Renderer>(renderer: &mut R, steps: &[Step]) {
+    for step in steps {
+        match step {
+            &Step::Message(ref message) => renderer.send(message),
+            &Step::Point(ref point) => renderer.stroke(point.clone()),
+        }
+    }
+}
+
+pub fn render_headless(w: f64, h: f64, steps: &[Step]) -> CanvasImage {
+    let mut canvas = Canvas::new(w, h);
+    replay(&mut canvas, steps);
+    canvas.frame_image()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f64, y: f64, dragging: bool) -> Step {
+        Step::Point(StrokePoint { x: x, y: y, pressure: 0.0, tilt_x: 0.0, tilt_y: 0.0, timestamp: (x * 10.0) as i64, dragging: dragging })
+    }
+
+    // A horizontal line through the middle of a 16x16 canvas.
+    fn line() -> Vec<Step> {
+        let mut steps: Vec<Step> = (2..14).map(|x| point(x as f64, 8.0, true)).collect();
+        steps.push(point(14.0, 8.0, false));
+        steps
+    }
+
+    #[test]
+    fn headless_stroke_is_drawn() {
+        let image = render_headless(16.0, 16.0, &line());
+        assert_eq!((image.width(), image.height()), (16, 16));
+        let on = image.pixel(8, 8);
+        assert!(on[3] > 128, "{:?}", on);
+        // the default brush is black
+        assert!(on[0] == 0 && on[1] == 0 && on[2] == 0);
+        assert_eq!(image.pixel(8, 1), [0, 0, 0, 0]);
+        assert_eq!(image.pixel(8, 14), [0, 0, 0, 0]);
+        assert_eq!(image.pixel(15, 8), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn messages_are_replayed_in_order() {
+        let mut steps = line();
+        steps.push(Step::Message(Message::Undo));
+        let image = render_headless(16.0, 16.0, &steps);
+        assert!(image.data().iter().all(|&v| v == 0));
+        steps.push(Step::Message(Message::Redo));
+        assert_eq!(render_headless(16.0, 16.0, &steps).data(), render_headless(16.0, 16.0, &line()).data());
+    }
+}
@@ -0,0 +1,40 @@
+use message::{Message, Topic};
+use reactive::property::Property;
+use reactive::widget::{HandlerType, Model};
+
+// Result of the last edge matching, as text for a label.
+pub struct ScoreModel {
+    text: Property<String>,
+}
+
+fn format_score(score: f64) -> String {
+    if score.is_finite() {
+        format!("score: {:.2}", score)
+    } else {
+        "score: no edges".to_string()
+    }
+}
+
+impl ScoreModel {
+    pub fn new() -> ScoreModel {
+        ScoreModel {
+            text: Property::new("score: -".to_string()),
+        }
+    }
+
+    pub fn text(&self) -> Property<String> {
+        self.text.clone()
+    }
+}
+
+impl Model<Message> for ScoreModel {
+    fn update(&mut self, message: &Message, widget_handler: &mut HandlerType) {
+        if let &Message::MatchScored(score) = message {
+            self.text.set(format_score(score));
+        }
+    }
+
+    fn subscriptions(&self) -> Vec<Topic> {
+        vec![Topic::Score]
+    }
+}
@@ -0,0 +1,318 @@
+// Compares a drawing with a target picture by their edges.
+//
+// Both images are reduced to thin edge maps (Sobel gradient with non-maximum
+// suppression). The drawing is moved so that the medians of the two edge
+// point sets coincide, and the score is the symmetric chamfer distance: the
+// mean distance in pixels from every edge point to the nearest edge point of
+// the other image, averaged over both directions. Lower is better, and 0 is
+// a perfect match.
+//
+// This is not the fitness value the old bin/edge tool printed: scores from
+// before cannot be compared with these.
+
+use app::canvas::CanvasImage;
+use app::color::{Color, Channel};
+
+const EDGE_THRESHOLD: f64 = 0.2; // relative to the strongest gradient
+const MIN_GRADIENT: f64 = 8.0;
+const INF: f64 = 1e20;
+
+pub struct MatchResult {
+    pub score: f64,
+    pub offset: (i32, i32), // where the drawing's origin lands on the target
+    pub overlay: CanvasImage, // drawing sized, target edges in blue and drawing edges in red
+}
+
+pub struct EdgeMap {
+    pub width: usize,
+    pub height: usize,
+    pub edges: Vec<bool>,
+}
+
+impl EdgeMap {
+    fn points(&self) -> Vec<(i32, i32)> {
+        let mut points = vec![];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.edges[y * self.width + x] {
+                    points.push((x as i32, y as i32));
+                }
+            }
+        }
+        points
+    }
+}
+
+// Luminance in 0..255 with transparent pixels on a white background.
+fn grayscale<T: Channel>(image: &CanvasImage<T>) -> Vec<f64> {
+    // premultiplied, so the luminance already is scaled by alpha
+    image.data().chunks(4).map(|p| {
+        let l = 0.299 * p[0].to_f64() + 0.587 * p[1].to_f64() + 0.114 * p[2].to_f64();
+        (l + 1.0 - p[3].to_f64()) * 255.0
+    }).collect()
+}
+
+fn blur(src: &Vec<f64>, w: usize, h: usize) -> Vec<f64> {
+    let kernel = [1.0, 2.0, 1.0];
+    let mut tmp = vec![0.0; w * h];
+    let mut dst = vec![0.0; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let mut v = 0.0;
+            for k in 0..3 {
+                let sx = (x as i32 + k as i32 - 1).max(0).min(w as i32 - 1) as usize;
+                v += kernel[k] * src[y * w + sx];
+            }
+            tmp[y * w + x] = v / 4.0;
+        }
+    }
+    for y in 0..h {
+        for x in 0..w {
+            let mut v = 0.0;
+            for k in 0..3 {
+                let sy = (y as i32 + k as i32 - 1).max(0).min(h as i32 - 1) as usize;
+                v += kernel[k] * tmp[sy * w + x];
+            }
+            dst[y * w + x] = v / 4.0;
+        }
+    }
+    dst
+}
+
+pub fn detect_edges(image: &CanvasImage) -> EdgeMap {
+    let w = image.width() as usize;
+    let h = image.height() as usize;
+    let gray = blur(&grayscale(image), w, h);
+    let mut gx = vec![0.0; w * h];
+    let mut gy = vec![0.0; w * h];
+    let mut mag = vec![0.0; w * h];
+    let mut max_mag: f64 = 0.0;
+    for y in 1..h.saturating_sub(1) {
+        for x in 1..w.saturating_sub(1) {
+            let p = |dx: i32, dy: i32| gray[(y as i32 + dy) as usize * w + (x as i32 + dx) as usize];
+            let i = y * w + x;
+            gx[i] = (p(1, -1) + 2.0 * p(1, 0) + p(1, 1)) - (p(-1, -1) + 2.0 * p(-1, 0) + p(-1, 1));
+            gy[i] = (p(-1, 1) + 2.0 * p(0, 1) + p(1, 1)) - (p(-1, -1) + 2.0 * p(0, -1) + p(1, -1));
+            mag[i] = (gx[i] * gx[i] + gy[i] * gy[i]).sqrt();
+            max_mag = max_mag.max(mag[i]);
+        }
+    }
+
+    let threshold = (max_mag * EDGE_THRESHOLD).max(MIN_GRADIENT);
+    let mut edges = vec![false; w * h];
+    for y in 1..h.saturating_sub(1) {
+        for x in 1..w.saturating_sub(1) {
+            let i = y * w + x;
+            if mag[i] < threshold {
+                continue;
+            }
+            // keep only local maxima across the gradient direction
+            let angle = gy[i].atan2(gx[i]).to_degrees();
+            let angle = if angle < 0.0 { angle + 180.0 } else { angle };
+            let (dx, dy) = if angle < 22.5 || angle >= 157.5 {
+                (1, 0)
+            } else if angle < 67.5 {
+                (1, 1)
+            } else if angle < 112.5 {
+                (0, 1)
+            } else {
+                (-1, 1)
+            };
+            let a = mag[((y as i32 + dy) as usize) * w + (x as i32 + dx) as usize];
+            let b = mag[((y as i32 - dy) as usize) * w + (x as i32 - dx) as usize];
+            edges[i] = mag[i] >= a && mag[i] >= b;
+        }
+    }
+    EdgeMap {
+        width: w,
+        height: h,
+        edges: edges,
+    }
+}
+
+// 1D squared distance transform (Felzenszwalb & Huttenlocher).
+fn distance_transform_1d(f: &[f64], d: &mut [f64]) {
+    let n = f.len();
+    let mut v = vec![0; n];
+    let mut z = vec![0.0; n + 1];
+    let mut k = 0;
+    z[0] = ::std::f64::NEG_INFINITY;
+    z[1] = ::std::f64::INFINITY;
+    for q in 1..n {
+        let mut s;
+        loop {
+            let p = v[k];
+            s = ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2.0 * q as f64 - 2.0 * p as f64);
+            if s > z[k] {
+                break;
+            }
+            k -= 1;
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = ::std::f64::INFINITY;
+    }
+    k = 0;
+    for q in 0..n {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let p = v[k];
+        d[q] = (q as f64 - p as f64).powi(2) + f[p];
+    }
+}
+
+// Euclidean distance from every pixel to the nearest edge.
+fn distance_field(map: &EdgeMap) -> Vec<f64> {
+    let (w, h) = (map.width, map.height);
+    let mut field: Vec<f64> = map.edges.iter().map(|&e| if e { 0.0 } else { INF }).collect();
+    let mut column = vec![0.0; h];
+    let mut out = vec![0.0; h.max(w)];
+    for x in 0..w {
+        for y in 0..h {
+            column[y] = field[y * w + x];
+        }
+        distance_transform_1d(&column, &mut out[..h]);
+        for y in 0..h {
+            field[y * w + x] = out[y];
+        }
+    }
+    for y in 0..h {
+        let row = field[y * w..(y + 1) * w].to_vec();
+        distance_transform_1d(&row, &mut out[..w]);
+        field[y * w..(y + 1) * w].copy_from_slice(&out[..w]);
+    }
+    field.iter().map(|d| d.sqrt()).collect()
+}
+
+fn median(mut values: Vec<i32>) -> i32 {
+    values.sort();
+    values[values.len() / 2]
+}
+
+// Mean distance from `points` (shifted by `offset`) to the nearest edge of `field`.
+fn mean_distance(points: &Vec<(i32, i32)>, offset: (i32, i32), field: &Vec<f64>, w: usize, h: usize) -> f64 {
+    let mut sum = 0.0;
+    for &(x, y) in points {
+        let tx = (x + offset.0).max(0).min(w as i32 - 1) as usize;
+        let ty = (y + offset.1).max(0).min(h as i32 - 1) as usize;
+        let outside = ((x + offset.0 - tx as i32).abs() + (y + offset.1 - ty as i32).abs()) as f64;
+        sum += field[ty * w + tx] + outside;
+    }
+    sum / points.len() as f64
+}
+
+pub fn match_edges(drawing: &CanvasImage, target: &CanvasImage) -> MatchResult {
+    let drawing_edges = detect_edges(drawing);
+    let target_edges = detect_edges(target);
+    let drawing_points = drawing_edges.points();
+    let target_points = target_edges.points();
+    let (dw, dh) = (drawing_edges.width, drawing_edges.height);
+    let (tw, th) = (target_edges.width, target_edges.height);
+    let mut overlay = CanvasImage::new(dw as u32, dh as u32, 0);
+
+    if drawing_points.is_empty() || target_points.is_empty() {
+        return MatchResult {
+            score: ::std::f64::INFINITY,
+            offset: (0, 0),
+            overlay: overlay,
+        };
+    }
+
+    let offset = (median(target_points.iter().map(|p| p.0).collect()) - median(drawing_points.iter().map(|p| p.0).collect()),
+                  median(target_points.iter().map(|p| p.1).collect()) - median(drawing_points.iter().map(|p| p.1).collect()));
+
+    let target_field = distance_field(&target_edges);
+    let drawing_field = distance_field(&drawing_edges);
+    let forward = mean_distance(&drawing_points, offset, &target_field, tw, th);
+    let backward = mean_distance(&target_points, (-offset.0, -offset.1), &drawing_field, dw, dh);
+
+    for &(x, y) in &target_points {
+        let (ox, oy) = (x - offset.0, y - offset.1);
+        if ox >= 0 && oy >= 0 && (ox as usize) < dw && (oy as usize) < dh {
+            overlay.set_color(ox as usize, oy as usize, Color::new(0, 0, 255, 255));
+        }
+    }
+    for &(x, y) in &drawing_points {
+        let (tx, ty) = (x + offset.0, y + offset.1);
+        let matched = tx >= 0 && ty >= 0 && (tx as usize) < tw && (ty as usize) < th &&
+            target_field[ty as usize * tw + tx as usize] < 1.5;
+        let color = if matched {
+            Color::new(255, 0, 255, 255)
+        } else {
+            Color::new(255, 0, 0, 255)
+        };
+        overlay.set_color(x as usize, y as usize, color);
+    }
+
+    MatchResult {
+        score: (forward + backward) / 2.0,
+        offset: offset,
+        overlay: overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // White with a black rectangle from (x0, y0) to (x1, y1), exclusive.
+    fn rectangle(w: u32, h: u32, x0: u32, y0: u32, x1: u32, y1: u32) -> CanvasImage {
+        let mut data = vec![255; (w * h * 4) as usize];
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let i = ((y * w + x) * 4) as usize;
+                data[i..i + 3].copy_from_slice(&[0, 0, 0]);
+            }
+        }
+        CanvasImage::from_straight(w, h, data)
+    }
+
+    #[test]
+    fn drawing_matches_itself() {
+        let image = rectangle(32, 24, 8, 6, 20, 16);
+        let result = match_edges(&image, &image);
+        assert_eq!(result.offset, (0, 0));
+        assert_eq!(result.score, 0.0);
+    }
+
+    #[test]
+    fn translation_is_the_offset() {
+        let drawing = rectangle(32, 24, 6, 5, 16, 13);
+        let target = rectangle(32, 24, 11, 8, 21, 16);
+        let result = match_edges(&drawing, &target);
+        assert_eq!(result.offset, (5, 3));
+        assert!(result.score < 0.5);
+    }
+
+    #[test]
+    fn nothing_to_match_is_infinite() {
+        let blank = CanvasImage::new(16, 16, 255);
+        let shape = rectangle(16, 16, 4, 4, 12, 12);
+        assert!(detect_edges(&blank).points().is_empty());
+        assert_eq!(match_edges(&blank, &shape).score, ::std::f64::INFINITY);
+        assert_eq!(match_edges(&shape, &blank).score, ::std::f64::INFINITY);
+    }
+
+    #[test]
+    fn distance_transform_is_the_lower_envelope() {
+        let f = [INF, 3.0, INF, INF, 0.0, INF, 1.0, INF, INF];
+        let mut d = [0.0; 9];
+        distance_transform_1d(&f, &mut d);
+        for q in 0..f.len() {
+            let brute = (0..f.len()).map(|p| (q as f64 - p as f64).powi(2) + f[p]).fold(INF, f64::min);
+            assert_eq!(d[q], brute);
+        }
+    }
+
+    #[test]
+    fn distance_field_is_euclidean() {
+        let mut edges = vec![false; 5 * 4];
+        edges[1 * 5 + 1] = true;
+        let field = distance_field(&EdgeMap { width: 5, height: 4, edges: edges });
+        assert_eq!(field[1 * 5 + 1], 0.0);
+        assert_eq!(field[1 * 5 + 4], 3.0);
+        assert_eq!(field[3 * 5 + 3], 8.0f64.sqrt());
+    }
+}
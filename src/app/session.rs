@@ -0,0 +1,278 @@
+// Recording and playback of input sessions.
+//
+// A session log is a text file with one event per line, prefixed with the
+// milliseconds since recording started:
+//
+//     120 point 31.5 40 0 0 0 1500000000 1
+//     130 mouse 32 41 0 0 1
+//
+// `point` is a StrokePoint from the drawing area (x, y, pressure, tilt_x,
+// tilt_y, timestamp, dragging) and `mouse` a Message::CanvasMouseEvent
+// (x, y, down, up, dragging). Flags are written as 0 or 1.
+
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use message::Message;
+
+use app::render::{Renderer, Step};
+use app::stroke::StrokePoint;
+
+const HEADER: &'static str = "# sketchbook session 1";
+
+pub struct Event {
+    pub time: u64, // ms since the start of the recording
+    pub step: Step,
+}
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+fn flag(b: bool) -> u8 {
+    if b { 1 } else { 0 }
+}
+
+// Only mouse input is recorded; other steps give None.
+fn format_step(step: &Step) -> Option<String> {
+    match step {
+        &Step::Point(ref p) => Some(format!("point {} {} {} {} {} {} {}",
+                                            p.x, p.y, p.pressure, p.tilt_x, p.tilt_y, p.timestamp, flag(p.dragging))),
+        &Step::Message(Message::CanvasMouseEvent { x, y, down, up, dragging }) =>
+            Some(format!("mouse {} {} {} {} {}", x, y, flag(down), flag(up), flag(dragging))),
+        _ => None,
+    }
+}
+
+fn parse_event(n: usize, line: &str) -> io::Result<Event> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let number = |i: usize| -> io::Result<f64> {
+        match fields.get(i) {
+            Some(f) => f.parse().map_err(|_| invalid(n, "malformed number")),
+            None => Err(invalid(n, "missing field")),
+        }
+    };
+    let time = number(0)? as u64;
+    let step = match fields.get(1) {
+        Some(&"point") => Step::Point(StrokePoint {
+            x: number(2)?,
+            y: number(3)?,
+            pressure: number(4)?,
+            tilt_x: number(5)?,
+            tilt_y: number(6)?,
+            timestamp: number(7)? as i64,
+            dragging: number(8)? != 0.0,
+        }),
+        Some(&"mouse") => Step::Message(Message::CanvasMouseEvent {
+            x: number(2)?,
+            y: number(3)?,
+            down: number(4)? != 0.0,
+            up: number(5)? != 0.0,
+            dragging: number(6)? != 0.0,
+        }),
+        _ => return Err(invalid(n, "unknown event")),
+    };
+    Ok(Event {
+        time: time,
+        step: step,
+    })
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Recorder {
+            out: out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, step: &Step) -> io::Result<()> {
+        if let Some(line) = format_step(step) {
+            writeln!(self.out, "{} {}", millis(self.start.elapsed()), line)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub fn read_log<R: BufRead>(r: R) -> io::Result<Vec<Event>> {
+    let mut events = vec![];
+    for (n, line) in r.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        events.push(parse_event(n + 1, line)?);
+    }
+    Ok(events)
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<Event>> {
+    read_log(BufReader::new(File::open(path)?))
+}
+
+pub fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+// Plays a log either in one go (`play`) or a bit at a time from an event
+// loop (`play_until`), with the pauses scaled by the speed.
+pub struct Player {
+    events: Vec<Event>,
+    speed: f64,
+    next: usize, // first event not played yet
+}
+
+impl Player {
+    pub fn new(events: Vec<Event>) -> Player {
+        Player {
+            events: events,
+            speed: 1.0,
+            next: 0,
+        }
+    }
+
+    // 2.0 plays twice as fast as recorded; 0 or less plays without waiting.
+    pub fn speed(mut self, speed: f64) -> Player {
+        self.speed = speed;
+        self
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.events.len()
+    }
+
+    // When the event is due, in ms since playback started.
+    fn due(&self, event: &Event) -> u64 {
+        if self.speed <= 0.0 {
+            return 0;
+        }
+        let first = self.events[0].time;
+        (event.time.saturating_sub(first) as f64 / self.speed) as u64
+    }
+
+    // Plays the events due `elapsed` ms after playback started.
+    pub fn play_until<R: Renderer>(&mut self, elapsed: u64, renderer: &mut R) {
+        while !self.finished() && self.due(&self.events[self.next]) <= elapsed {
+            match self.events[self.next].step {
+                Step::Message(ref message) => renderer.send(message),
+                Step::Point(ref point) => renderer.stroke(point.clone()),
+            }
+            self.next += 1;
+        }
+    }
+
+    // Plays the rest of the log, sleeping between events.
+    pub fn play<R: Renderer>(&mut self, renderer: &mut R) {
+        let start = Instant::now();
+        while !self.finished() {
+            let due = self.due(&self.events[self.next]);
+            let elapsed = millis(start.elapsed());
+            if due > elapsed {
+                thread::sleep(Duration::from_millis(due - elapsed));
+            }
+            self.play_until(due, renderer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn point(x: f64, dragging: bool) -> Step {
+        Step::Point(StrokePoint { x: x, y: 2.5, pressure: 0.75, tilt_x: 0.0, tilt_y: 0.0, timestamp: 1500000000, dragging: dragging })
+    }
+
+    // Keeps what it was sent, as text.
+    struct Log(Vec<String>);
+
+    impl Renderer for Log {
+        fn send(&mut self, message: &Message) {
+            self.0.push(format_step(&Step::Message(message.clone())).unwrap());
+        }
+
+        fn stroke(&mut self, point: StrokePoint) {
+            self.0.push(format_step(&Step::Point(point)).unwrap());
+        }
+
+        fn frame(&self) -> ::app::canvas::CanvasImage {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn recorder_round_trip() {
+        let path = env::temp_dir().join(format!("sketch_book_session_{}.log", ::std::process::id()));
+        let steps = vec![
+            point(1.0, true),
+            Step::Message(Message::CanvasMouseEvent { x: 3.0, y: 4.5, down: true, up: false, dragging: true }),
+            Step::Message(Message::Undo), // not mouse input, left out
+            point(2.0, false),
+        ];
+        let mut recorder = Recorder::create(&path).unwrap();
+        for step in &steps {
+            recorder.record(step).unwrap();
+        }
+        recorder.finish().unwrap();
+        let events = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let read: Vec<String> = events.iter().map(|e| format_step(&e.step).unwrap()).collect();
+        let written: Vec<String> = steps.iter().filter_map(format_step).collect();
+        assert_eq!(read, written);
+        assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        let log = "# sketchbook session 1\n10 point 1 2 0 0 0 5 1\n20 point 1 x 0 0 0 5 1\n";
+        let err = read_log(Cursor::new(log)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 3:"));
+        assert!(read_log(Cursor::new("10 point 1 2\n")).is_err());
+        assert!(read_log(Cursor::new("10 pen 1 2 0 0 0 5 1\n")).is_err());
+    }
+
+    #[test]
+    fn playback_follows_the_recorded_times() {
+        let log = "100 point 1 2 0 0 0 5 1\n150 point 2 2 0 0 0 5 1\n500 point 3 2 0 0 0 5 0\n";
+        let mut player = Player::new(read_log(Cursor::new(log)).unwrap()).speed(2.0);
+        let mut out = Log(vec![]);
+        player.play_until(0, &mut out);
+        assert_eq!(out.0.len(), 1);
+        // 50 ms recorded are 25 ms at double speed
+        player.play_until(24, &mut out);
+        assert_eq!(out.0.len(), 1);
+        player.play_until(25, &mut out);
+        assert_eq!(out.0.len(), 2);
+        assert!(!player.finished());
+        player.play_until(200, &mut out);
+        assert!(player.finished());
+        assert_eq!(out.0[2], "point 3 2 0 0 0 5 0");
+
+        let mut player = Player::new(read_log(Cursor::new(log)).unwrap()).speed(0.0);
+        let mut out = Log(vec![]);
+        player.play_until(0, &mut out);
+        assert!(player.finished());
+        assert_eq!(out.0.len(), 3);
+    }
+}
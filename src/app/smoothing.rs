@@ -0,0 +1,260 @@
+// Steadier strokes.
+//
+// A stabilizer filters pen input while the stroke is drawn, so the line on
+// screen is already the steady one. Smoothing runs once the stroke is done:
+// the points are replaced by a spline through them (Catmull-Rom) or by
+// Bézier curves fitted to them (see `curve`) and the stroke is drawn again.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use app::curve;
+use app::stroke::StrokePoint;
+
+const SAMPLE_DISTANCE: f64 = 2.0; // px between the points of a smoothed stroke
+const FIT_TOLERANCE: f64 = 2.0; // px the fitted curve may stray from the points
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StabilizerKind {
+    None,
+    MovingAverage(usize), // over this many samples
+    LazyRope(f64),        // the brush only follows once the pen is this far away (px)
+    OneEuro { min_cutoff: f64, beta: f64 }, // cutoff in Hz, beta per px/s
+}
+
+// One-euro filter for a single value (Casiez et al. 2012).
+struct OneEuro {
+    value: f64,
+    derivative: f64,
+}
+
+fn smoothing_factor(cutoff: f64, dt: f64) -> f64 {
+    let tau = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+impl OneEuro {
+    fn filter(&mut self, x: f64, dt: f64, min_cutoff: f64, beta: f64) -> f64 {
+        let d = (x - self.value) / dt;
+        self.derivative += smoothing_factor(1.0, dt) * (d - self.derivative);
+        let cutoff = min_cutoff + beta * self.derivative.abs();
+        self.value += smoothing_factor(cutoff, dt) * (x - self.value);
+        self.value
+    }
+}
+
+pub struct Stabilizer {
+    kind: StabilizerKind,
+    recent: VecDeque<StrokePoint>,
+    last: Option<StrokePoint>, // what was handed out last
+    euro: Option<(OneEuro, OneEuro)>,
+}
+
+impl Stabilizer {
+    pub fn new(kind: StabilizerKind) -> Stabilizer {
+        Stabilizer {
+            kind: kind,
+            recent: VecDeque::new(),
+            last: None,
+            euro: None,
+        }
+    }
+
+    // Forgets the stroke; call when the pen is lifted.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.last = None;
+        self.euro = None;
+    }
+
+    // The point to draw for pen input `p`, or None when the brush stays put.
+    pub fn filter(&mut self, p: StrokePoint) -> Option<StrokePoint> {
+        let filtered = match self.kind {
+            StabilizerKind::None => Some(p),
+            StabilizerKind::MovingAverage(n) => {
+                self.recent.push_back(p.clone());
+                while self.recent.len() > n.max(1) {
+                    self.recent.pop_front();
+                }
+                let k = self.recent.len() as f64;
+                let mut q = p;
+                q.x = self.recent.iter().map(|r| r.x).sum::<f64>() / k;
+                q.y = self.recent.iter().map(|r| r.y).sum::<f64>() / k;
+                q.pressure = self.recent.iter().map(|r| r.pressure).sum::<f64>() / k;
+                Some(q)
+            },
+            StabilizerKind::LazyRope(length) => match self.last {
+                None => Some(p),
+                Some(ref last) => {
+                    let (dx, dy) = (p.x - last.x, p.y - last.y);
+                    let d = (dx * dx + dy * dy).sqrt();
+                    if d <= length {
+                        None
+                    } else {
+                        // pulled along until the rope is taut again
+                        let mut q = p.clone();
+                        q.x = last.x + dx * (d - length) / d;
+                        q.y = last.y + dy * (d - length) / d;
+                        Some(q)
+                    }
+                },
+            },
+            StabilizerKind::OneEuro { min_cutoff, beta } => {
+                let dt = match self.last {
+                    Some(ref last) => ((p.timestamp - last.timestamp).max(1) as f64) / 1000.0,
+                    None => 0.0,
+                };
+                let mut q = p.clone();
+                match self.euro {
+                    Some((ref mut fx, ref mut fy)) => {
+                        q.x = fx.filter(p.x, dt, min_cutoff, beta);
+                        q.y = fy.filter(p.y, dt, min_cutoff, beta);
+                    },
+                    None => {
+                        self.euro = Some((OneEuro { value: p.x, derivative: 0.0 },
+                                          OneEuro { value: p.y, derivative: 0.0 }));
+                    },
+                }
+                Some(q)
+            },
+        };
+        if let Some(ref q) = filtered {
+            self.last = Some(q.clone());
+        }
+        filtered
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Smoothing {
+    None,
+    CatmullRom,
+    Bezier,
+}
+
+fn lerp_point(p0: &StrokePoint, p1: &StrokePoint, t: f64) -> StrokePoint {
+    let lerp = |a: f64, b: f64| a + (b - a) * t;
+    StrokePoint {
+        x: lerp(p0.x, p1.x),
+        y: lerp(p0.y, p1.y),
+        pressure: lerp(p0.pressure, p1.pressure),
+        tilt_x: lerp(p0.tilt_x, p1.tilt_x),
+        tilt_y: lerp(p0.tilt_y, p1.tilt_y),
+        timestamp: lerp(p0.timestamp as f64, p1.timestamp as f64) as i64,
+        dragging: p1.dragging,
+    }
+}
+
+// Position on the segment p1-p2 of a uniform Catmull-Rom spline.
+fn catmull_rom(p0: f64, p1: f64, p2: f64, p3: f64, t: f64) -> f64 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+impl Smoothing {
+    pub fn apply(&self, points: &Vec<StrokePoint>) -> Vec<StrokePoint> {
+        let n = points.len();
+        match *self {
+            Smoothing::None => return points.clone(),
+            Smoothing::Bezier => return curve::resample_points(points, FIT_TOLERANCE, SAMPLE_DISTANCE),
+            Smoothing::CatmullRom => (),
+        }
+        if n < 3 {
+            return points.clone();
+        }
+        let at = |i: isize| &points[i.max(0).min(n as isize - 1) as usize];
+        let mut smoothed = vec![points[0].clone()];
+        for i in 0..n as isize - 1 {
+            let (p0, p1, p2, p3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+            let len = ((p2.x - p1.x).powi(2) + (p2.y - p1.y).powi(2)).sqrt();
+            let steps = (len / SAMPLE_DISTANCE).ceil().max(1.0) as usize;
+            for k in 1..steps + 1 {
+                let t = k as f64 / steps as f64;
+                let mut q = lerp_point(p1, p2, t);
+                q.x = catmull_rom(p0.x, p1.x, p2.x, p3.x, t);
+                q.y = catmull_rom(p0.y, p1.y, p2.y, p3.y, t);
+                smoothed.push(q);
+            }
+        }
+        smoothed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f64, y: f64, timestamp: i64) -> StrokePoint {
+        StrokePoint { x: x, y: y, pressure: 0.5, tilt_x: 0.0, tilt_y: 0.0, timestamp: timestamp, dragging: true }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn lazy_rope_holds_still_inside_its_radius() {
+        let mut s = Stabilizer::new(StabilizerKind::LazyRope(5.0));
+        assert_eq!(s.filter(point(0.0, 0.0, 0)).map(|p| p.x), Some(0.0));
+        assert!(s.filter(point(3.0, 4.0, 10)).is_none());
+        assert!(s.filter(point(-4.0, 0.0, 20)).is_none());
+        // 10 px away, pulled 5 px along
+        let q = s.filter(point(6.0, 8.0, 30)).unwrap();
+        assert!(close(q.x, 3.0) && close(q.y, 4.0));
+        // measured from where the brush is now, not from the first point
+        assert!(s.filter(point(6.0, 8.0, 40)).is_none());
+        s.reset();
+        assert!(s.filter(point(6.0, 8.0, 50)).is_some());
+    }
+
+    #[test]
+    fn moving_average_over_its_window() {
+        let mut s = Stabilizer::new(StabilizerKind::MovingAverage(3));
+        let xs: Vec<f64> = [0.0, 3.0, 6.0, 9.0, 12.0].iter().enumerate()
+            .map(|(i, &x)| s.filter(point(x, 1.0, i as i64 * 10)).unwrap().x)
+            .collect();
+        assert_eq!(xs, vec![0.0, 1.5, 3.0, 6.0, 9.0]);
+        s.reset();
+        assert_eq!(s.filter(point(12.0, 1.0, 60)).unwrap().x, 12.0);
+    }
+
+    #[test]
+    fn one_euro_converges_without_overshoot() {
+        let mut s = Stabilizer::new(StabilizerKind::OneEuro { min_cutoff: 1.0, beta: 0.01 });
+        assert_eq!(s.filter(point(0.0, 0.0, 0)).unwrap().x, 0.0);
+        let mut last = 0.0;
+        for i in 1..200 {
+            let x = s.filter(point(10.0, 0.0, i * 10)).unwrap().x;
+            assert!(x >= last && x <= 10.0);
+            last = x;
+        }
+        assert!((last - 10.0).abs() < 0.01, "{}", last);
+        // a held pen stays where it is
+        let mut s = Stabilizer::new(StabilizerKind::OneEuro { min_cutoff: 1.0, beta: 0.01 });
+        for i in 0..10 {
+            assert_eq!(s.filter(point(4.0, 2.0, i * 10)).unwrap().y, 2.0);
+        }
+    }
+
+    #[test]
+    fn smoothing_keeps_the_endpoints() {
+        let points: Vec<StrokePoint> = (0..8).map(|i| {
+            let x = i as f64 * 6.0;
+            point(x, if i % 2 == 0 { 0.0 } else { 5.0 }, i * 10)
+        }).collect();
+        for &smoothing in [Smoothing::CatmullRom, Smoothing::Bezier].iter() {
+            let smoothed = smoothing.apply(&points);
+            let (first, last) = (&smoothed[0], &smoothed[smoothed.len() - 1]);
+            assert!(close(first.x, 0.0) && close(first.y, 0.0), "{:?}", smoothing);
+            assert!(close(last.x, 42.0) && close(last.y, 5.0), "{:?}", smoothing);
+            assert!(close(last.pressure, 0.5));
+            // resampled at most SAMPLE_DISTANCE apart
+            assert!(smoothed.windows(2).all(|w| ((w[1].x - w[0].x).powi(2) + (w[1].y - w[0].y).powi(2)).sqrt() <= SAMPLE_DISTANCE * 1.5));
+        }
+        // Catmull-Rom runs through every point it was given
+        let smoothed = Smoothing::CatmullRom.apply(&points);
+        assert!(points.iter().all(|p| smoothed.iter().any(|q| close(p.x, q.x) && close(p.y, q.y))));
+        assert_eq!(Smoothing::None.apply(&points).len(), points.len());
+    }
+}
@@ -0,0 +1,106 @@
+// Places brush dabs along a stroke at even distances.
+//
+// The distance between dabs is `brush.spacing` times the brush diameter. The
+// stamper remembers how far along it is, so a stroke fed to it one segment at
+// a time gets the same dabs as when it is fed at once.
+
+use app::vector::Vec2d;
+use app::brush::Brush;
+
+const MIN_STEP: f64 = 0.25; // px, keeps tiny brushes from stamping forever
+
+pub struct Stamper {
+    to_next: f64, // distance along the stroke until the next dab
+}
+
+impl Stamper {
+    pub fn new() -> Stamper {
+        Stamper {
+            to_next: 0.0,
+        }
+    }
+
+    pub fn step(brush: &Brush) -> f64 {
+        (brush.spacing * brush.size * 2.0).max(MIN_STEP)
+    }
+
+    // Where the dabs on the segment p0-p1 go, as fractions of the segment.
+    // The first segment of a stroke should start at its first point (a
+    // zero-length segment is fine), which gets a dab.
+    pub fn advance(&mut self, p0: Vec2d, p1: Vec2d, brush: &Brush) -> Vec<f64> {
+        let step = Stamper::step(brush);
+        let d = p1 - p0;
+        let len = d.len();
+        let mut dabs = vec![];
+        let mut t = self.to_next;
+        while t <= len {
+            dabs.push(if len > 0.0 { t / len } else { 0.0 });
+            t += step;
+        }
+        self.to_next = t - len;
+        dabs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brush() -> Brush {
+        let mut brush = Brush::new();
+        brush.size = 2.0;
+        brush.spacing = 0.35; // a step of 1.4 px
+        brush
+    }
+
+    // Arc length at each dab when the polyline is fed one segment at a
+    // time, starting with the zero-length segment at its first point.
+    fn dab_lengths(points: &[Vec2d]) -> Vec<f64> {
+        let brush = brush();
+        let mut stamper = Stamper::new();
+        let mut lengths = vec![];
+        let mut start = 0.0;
+        let mut p0 = points[0];
+        for &p1 in points {
+            let len = (p1 - p0).len();
+            for t in stamper.advance(p0, p1, &brush) {
+                lengths.push(start + t * len);
+            }
+            start += len;
+            p0 = p1;
+        }
+        lengths
+    }
+
+    fn close(a: &[f64], b: &[f64]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-9)
+    }
+
+    #[test]
+    fn segments_give_the_dabs_of_the_whole_line() {
+        let whole = dab_lengths(&[Vec2d::new(0.0, 0.0), Vec2d::new(20.0, 0.0)]);
+        let pieces = dab_lengths(&[Vec2d::new(0.0, 0.0), Vec2d::new(3.3, 0.0), Vec2d::new(7.0, 0.0),
+                                   Vec2d::new(7.0, 0.0), Vec2d::new(20.0, 0.0)]);
+        assert_eq!(whole.len(), 15);
+        assert!(close(&whole, &pieces));
+    }
+
+    #[test]
+    fn spacing_carries_across_corners() {
+        // 5 + 6 + 8 px
+        let lengths = dab_lengths(&[Vec2d::new(0.0, 0.0), Vec2d::new(3.0, 4.0), Vec2d::new(3.0, 10.0), Vec2d::new(11.0, 10.0)]);
+        let step = Stamper::step(&brush());
+        let expected: Vec<f64> = (0..14).map(|i| i as f64 * step).collect();
+        assert!(close(&lengths, &expected));
+    }
+
+    #[test]
+    fn zero_length_start_is_one_dab() {
+        let p = Vec2d::new(4.0, 5.0);
+        let mut stamper = Stamper::new();
+        assert_eq!(stamper.advance(p, p, &brush()), vec![0.0]);
+        // the next dab is a whole step away
+        assert!(close(&stamper.advance(p, Vec2d::new(5.0, 5.0), &brush()), &[]));
+        assert!(close(&stamper.advance(Vec2d::new(5.0, 5.0), Vec2d::new(6.0, 5.0), &brush()), &[0.4]));
+    }
+}
@@ -1,5 +1,7 @@
 use app::vector::Vec2d;
 use app::brush::Brush;
+use app::curve;
+use app::curve::BezierPath;
 use std::ops::{Index, Sub};
 
 #[derive(Clone)]
@@ -9,7 +11,7 @@ pub struct StrokePoint {
     pub pressure: f64,
     pub tilt_x: f64,
     pub tilt_y: f64,
-    pub timestamp: i64,
+    pub timestamp: i64, // ms
     pub dragging: bool,
 }
 
@@ -51,4 +53,23 @@ impl Stroke {
     pub fn push(&mut self, item: StrokePoint) {
         self.points.push(item);
     }
+
+    pub fn positions(&self) -> Vec<Vec2d> {
+        self.points.iter().map(|p| Vec2d::new(p.x, p.y)).collect()
+    }
+
+    // Smooth curve no farther than `tolerance` px from any point.
+    pub fn fit_curve(&self, tolerance: f64) -> BezierPath {
+        BezierPath::fit(&self.positions(), tolerance)
+    }
+
+    // The stroke moved onto `path`, its fitted curve, with points `spacing`
+    // px apart.
+    pub fn resampled(&self, path: &BezierPath, spacing: f64) -> Stroke {
+        Stroke {
+            points: curve::resample_along(&self.points, path, spacing),
+            brush: self.brush.clone(),
+            finished: self.finished,
+        }
+    }
 }
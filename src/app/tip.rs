@@ -0,0 +1,236 @@
+// Brush tip shapes.
+//
+// A tip tells the stamping code how much of a pixel a dab covers. Offsets are
+// measured from the dab center to the pixel center, in pixels, and `size` is
+// the dab radius (half the width of the shape along its widest axis).
+
+use app::canvas::{saturate, CanvasImage};
+use app::color::Channel;
+
+pub trait Tip {
+    // Farthest distance from the center at which the tip covers anything.
+    fn reach(&self, size: f64) -> f64;
+    // Coverage in 0..1 of the pixel at (dx, dy).
+    fn coverage(&self, dx: f64, dy: f64, size: f64) -> f64;
+}
+
+// Disc with a one pixel anti-aliased edge.
+pub struct RoundHard;
+
+impl Tip for RoundHard {
+    fn reach(&self, size: f64) -> f64 {
+        size + 0.5
+    }
+
+    fn coverage(&self, dx: f64, dy: f64, size: f64) -> f64 {
+        saturate(size + 0.5 - (dx * dx + dy * dy).sqrt(), 0.0, 1.0)
+    }
+}
+
+// Gaussian falloff, with the radius at two standard deviations.
+pub struct RoundSoft;
+
+impl Tip for RoundSoft {
+    fn reach(&self, size: f64) -> f64 {
+        size * 2.0 // four standard deviations, where the falloff is below 1/255
+    }
+
+    fn coverage(&self, dx: f64, dy: f64, size: f64) -> f64 {
+        let sigma = size / 2.0;
+        (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+    }
+}
+
+// Axis aligned square with anti-aliased edges.
+pub struct Square;
+
+impl Tip for Square {
+    fn reach(&self, size: f64) -> f64 {
+        (size + 0.5) * 2.0f64.sqrt()
+    }
+
+    fn coverage(&self, dx: f64, dy: f64, size: f64) -> f64 {
+        saturate(size + 0.5 - dx.abs(), 0.0, 1.0) * saturate(size + 0.5 - dy.abs(), 0.0, 1.0)
+    }
+}
+
+// Ellipse like a broad nib: `aspect` is the minor axis over the major one and
+// `angle` (radians) turns the major axis away from horizontal.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Calligraphic {
+    pub aspect: f64,
+    pub angle: f64,
+}
+
+impl Tip for Calligraphic {
+    fn reach(&self, size: f64) -> f64 {
+        size + 0.5
+    }
+
+    fn coverage(&self, dx: f64, dy: f64, size: f64) -> f64 {
+        let k = saturate(self.aspect, 0.05, 1.0);
+        let (sin, cos) = self.angle.sin_cos();
+        let u = dx * cos + dy * sin;
+        let v = (-dx * sin + dy * cos) / k;
+        let d = (u * u + v * v).sqrt();
+        if d == 0.0 {
+            return 1.0;
+        }
+        // distance to the edge estimated from the gradient of d; the estimate
+        // runs long near the tight ends, and the nib never leaves its circle
+        let grad = ((u * u + v * v / (k * k)) / (d * d)).sqrt();
+        let r = (dx * dx + dy * dy).sqrt();
+        saturate(((size - d) / grad).min(size - r) + 0.5, 0.0, 1.0)
+    }
+}
+
+// Grayscale bitmap stretched over the dab, dark is paint.
+#[derive(PartialEq, Debug)]
+pub struct Stamp {
+    width: usize,
+    height: usize,
+    coverage: Vec<f64>,
+}
+
+impl Stamp {
+    pub fn new(width: usize, height: usize, coverage: Vec<f64>) -> Stamp {
+        assert_eq!(coverage.len(), width * height);
+        Stamp {
+            width: width,
+            height: height,
+            coverage: coverage,
+        }
+    }
+
+    // Transparent pixels count as white.
+    pub fn from_image<T: Channel>(image: &CanvasImage<T>) -> Stamp {
+        // premultiplied, so (1 - l) a is a minus the stored luminance
+        let coverage = image.data().chunks(4).map(|p| {
+            let l = 0.299 * p[0].to_f64() + 0.587 * p[1].to_f64() + 0.114 * p[2].to_f64();
+            (p[3].to_f64() - l).max(0.0)
+        }).collect();
+        Stamp::new(image.width() as usize, image.height() as usize, coverage)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn coverage_data(&self) -> &Vec<f64> {
+        &self.coverage
+    }
+
+    fn at(&self, x: i64, y: i64) -> f64 {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return 0.0;
+        }
+        self.coverage[y as usize * self.width + x as usize]
+    }
+}
+
+impl Tip for Stamp {
+    fn reach(&self, size: f64) -> f64 {
+        // filtering spreads each edge by half a bitmap pixel
+        let n = self.width.max(self.height).max(1) as f64;
+        size * (1.0 + 1.0 / n) * 2.0f64.sqrt()
+    }
+
+    fn coverage(&self, dx: f64, dy: f64, size: f64) -> f64 {
+        if self.width == 0 || self.height == 0 || size <= 0.0 {
+            return 0.0;
+        }
+        // the longer side of the bitmap spans the dab's diameter
+        let scale = self.width.max(self.height) as f64 / (size * 2.0);
+        let x = dx * scale + self.width as f64 / 2.0 - 0.5;
+        let y = dy * scale + self.height as f64 / 2.0 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.at(x0, y0) * (1.0 - fx) + self.at(x0 + 1, y0) * fx;
+        let bottom = self.at(x0, y0 + 1) * (1.0 - fx) + self.at(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    // Largest coverage on a circle around the center.
+    fn max_at(tip: &Tip, r: f64, size: f64) -> f64 {
+        (0..64).map(|i| {
+            let (sin, cos) = (i as f64 * PI / 32.0).sin_cos();
+            tip.coverage(r * cos, r * sin, size)
+        }).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn nothing_beyond_reach() {
+        let stamp = Stamp::new(2, 2, vec![1.0; 4]);
+        let tips: Vec<&Tip> = vec![&RoundHard, &RoundSoft, &Square, &Calligraphic { aspect: 0.3, angle: 0.7 }, &stamp];
+        for tip in tips {
+            for &size in [0.5, 3.0, 10.0].iter() {
+                assert!(close(tip.coverage(0.0, 0.0, size), 1.0));
+                assert!(max_at(tip, tip.reach(size) + 1e-3, size) < 1.0 / 255.0);
+            }
+        }
+    }
+
+    #[test]
+    fn round_tips_at_the_edge() {
+        assert!(close(RoundHard.coverage(4.0, 0.0, 4.0), 0.5));
+        assert!(close(RoundHard.coverage(0.0, -3.0, 4.0), 1.0));
+        assert!(close(RoundHard.coverage(3.0, 4.0, 4.0), 0.0));
+        // the radius is at two standard deviations
+        assert!(close(RoundSoft.coverage(0.0, 4.0, 4.0), (-2.0f64).exp()));
+        assert!(close(RoundSoft.coverage(2.0, 0.0, 4.0), (-0.5f64).exp()));
+    }
+
+    #[test]
+    fn square_reaches_into_the_corners() {
+        assert!(close(Square.coverage(4.0, 0.0, 4.0), 0.5));
+        assert!(close(Square.coverage(4.0, 4.0, 4.0), 0.25));
+        assert!(close(Square.coverage(3.0, 3.0, 4.0), 1.0));
+        // farther than the round reach, still painted
+        assert!(Square.coverage(4.2, 4.2, 4.0) > 0.0);
+    }
+
+    #[test]
+    fn calligraphic_is_a_turned_ellipse() {
+        let flat = Calligraphic { aspect: 0.5, angle: 0.0 };
+        assert!(close(flat.coverage(4.0, 0.0, 4.0), 0.5));
+        assert!(close(flat.coverage(0.0, 2.0, 4.0), 0.5));
+        assert!(close(flat.coverage(0.0, 3.0, 4.0), 0.0));
+        let upright = Calligraphic { aspect: 0.5, angle: PI / 2.0 };
+        assert!(close(upright.coverage(0.0, 4.0, 4.0), 0.5));
+        assert!(close(upright.coverage(2.0, 0.0, 4.0), 0.5));
+        assert!(close(upright.coverage(3.0, 0.0, 4.0), 0.0));
+    }
+
+    #[test]
+    fn stamp_follows_the_image() {
+        // black, white, transparent and half transparent black, straight
+        let image = CanvasImage::<u8>::from_straight(2, 2, vec![
+            0, 0, 0, 255,  255, 255, 255, 255,
+            0, 0, 0, 0,    0, 0, 0, 128,
+        ]);
+        let stamp = Stamp::from_image(&image);
+        let c = stamp.coverage_data();
+        assert!(close(c[0], 1.0) && close(c[1], 0.0) && close(c[2], 0.0));
+        assert!(close(c[3], 128.0 / 255.0));
+        // pixel centers of the bitmap at a radius of 2
+        assert!(close(stamp.coverage(-1.0, -1.0, 2.0), 1.0));
+        assert!(close(stamp.coverage(1.0, -1.0, 2.0), 0.0));
+        assert!(close(stamp.coverage(0.0, -1.0, 2.0), 0.5));
+        assert!(close(stamp.coverage(0.0, 0.0, 0.0), 0.0));
+    }
+}
@@ -1,5 +1,4 @@
 use std::ops::{Add, Sub};
-use app::color::{Color};
 
 #[derive(Copy, Clone, Debug)]
 pub struct Vec2d {
@@ -103,13 +102,4 @@ impl Vec3d {
             self.clone()
         }
     }
-    pub fn to_color(self) -> Color<u8> {
-        let s = self.normalize().smul(127.5);
-        Color {
-            r: (s.x + 127.5) as u8,
-            g: (s.y + 127.5) as u8,
-            b: (s.z + 127.5) as u8,
-            a: 255,
-        }
-    }
 }
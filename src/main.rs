@@ -1,10 +1,15 @@
 extern crate ui;
+extern crate png;
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::env;
+use std::process;
+use std::path::Path;
+use std::f64::consts::PI;
 
 mod reactive;
-use reactive::widget::{Window, WindowHandler, Container, Button, ButtonHandler, Slider, SliderHandler, Area, AreaHandler, AreaDrawParams};
+use reactive::widget::{Window, WindowHandler, Menu, Container, Button, ButtonHandler, Slider, SliderHandler, Label, Checkbox, Entry, Combobox, Area, AreaHandler, AreaDrawParams};
 use reactive::widget::{Model, HandlerType, Component}; // TODO move to good place
 
 mod message;
@@ -12,46 +17,236 @@ use message::Message;
 
 mod app;
 use app::canvas;
+use app::canvas_model;
+use app::score;
+use app::brush;
+use app::brush::{BrushTip, BrushBlend};
+use app::blend;
+use app::tip::Calligraphic;
+use app::smoothing::{StabilizerKind, Smoothing};
+use app::session;
+use app::session::Player;
+use app::image_io;
 
-pub struct PoheModel {
-    value: i64,
-}
-
-impl PoheModel {
-    pub fn new() -> PoheModel {
-        PoheModel {
-            value: 0,
-        }
-    }
-}
-
-impl Model<Message> for PoheModel {
-    fn update(&mut self, message: &Message, widget_handler: &mut HandlerType) {
-    }
-}
-
-pub struct HoeModel {
-    count: i64,
-}
+const TIME_LAPSE_SPEED: f64 = 8.0;
 
-impl HoeModel {
-    pub fn new() -> HoeModel {
-        HoeModel {
-            count: 0,
-        }
-    }
-}
-
-impl Model<Message> for HoeModel {
-    fn update(&mut self, message: &Message, widget_handler: &mut HandlerType) {
+// sketch_book --replay <session log> <output image>
+// renders a recorded session without opening a window
+fn replay_session(log: &str, output: &str, width: f64, height: f64) -> bool {
+    let events = match session::load(Path::new(log)) {
+        Ok(events) => events,
+        Err(why) => {
+            println!("couldn't read {}: {}", log, why);
+            return false;
+        },
+    };
+    let mut canvas = canvas::Canvas::new(width, height);
+    Player::new(events).speed(0.0).play(&mut canvas);
+    if let Err(why) = image_io::save(Path::new(output), &canvas.frame_image()) {
+        println!("couldn't write {}: {}", output, why);
+        return false;
     }
+    true
 }
 
 fn main() {
     let width: f64 = 640.0;
     let height: f64 = 480.0;
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 4 && args[1] == "--replay" {
+        if !replay_session(&args[2], &args[3], width, height) {
+            process::exit(1);
+        }
+        return;
+    }
     reactive::init();
-    let components = Window::new("SketchBook", width as i32, height as i32, false)
+    Menu::new("File")
+        .item("Open...", |window| {
+            if let Some(path) = window.open_file() {
+                reactive::emit(Message::OpenDocument(path));
+            }
+        })
+        .item("Save", |window| {
+            reactive::emit(Message::Save);
+        })
+        .item("Save As...", |window| {
+            if let Some(path) = window.save_file() {
+                reactive::emit(Message::SaveDocument(path));
+            }
+        })
+        .separator()
+        .item("Export Image...", |window| {
+            if let Some(path) = window.save_file() {
+                reactive::emit(Message::ExportPng(path));
+            }
+        })
+        .separator()
+        .item("Record Session...", |window| {
+            if let Some(path) = window.save_file() {
+                reactive::emit(Message::StartRecording(path));
+            }
+        })
+        .item("Stop Recording", |window| {
+            reactive::emit(Message::StopRecording);
+        })
+        .item("Play Session...", |window| {
+            if let Some(path) = window.open_file() {
+                reactive::emit(Message::PlayRecording(path, 1.0));
+            }
+        })
+        .item("Play Time-lapse...", |window| {
+            if let Some(path) = window.open_file() {
+                reactive::emit(Message::PlayRecording(path, TIME_LAPSE_SPEED));
+            }
+        })
+        .separator()
+        .item("Quit", |window| {
+            reactive::quit();
+        });
+    Menu::new("Edit")
+        .item("Undo", |window| {
+            reactive::emit(Message::Undo);
+        })
+        .item("Redo", |window| {
+            reactive::emit(Message::Redo);
+        })
+        .separator()
+        .item("Clear", |window| {
+            reactive::emit(Message::ClearCanvasButton);
+        });
+    Menu::new("Layer")
+        .item("Import Image as Layer...", |window| {
+            if let Some(path) = window.open_file() {
+                reactive::emit(Message::ImportPngLayer(path));
+            }
+        })
+        .item("Close Stroke", |window| {
+            reactive::emit(Message::StrokeCloseButton);
+        })
+        .check_item("Show Overlay", true, |window, checked| {
+            reactive::emit(Message::OverlayVisible(checked));
+        });
+    Menu::new("Brush")
+        .item("Toggle Pen / Eraser", |window| {
+            reactive::emit(Message::BrushToggleButton);
+        })
+        .separator()
+        .item("Normal", |window| {
+            reactive::emit(Message::BrushBlendSelect(BrushBlend::Normal));
+        })
+        .item("Multiply", |window| {
+            reactive::emit(Message::BrushBlendSelect(BrushBlend::Multiply));
+        })
+        .item("Screen", |window| {
+            reactive::emit(Message::BrushBlendSelect(BrushBlend::Screen));
+        })
+        .item("Behind", |window| {
+            reactive::emit(Message::BrushBlendSelect(BrushBlend::Behind));
+        })
+        .item("Lock Alpha", |window| {
+            reactive::emit(Message::BrushBlendSelect(BrushBlend::LockAlpha));
+        })
+        .item("Bigger", |window| {
+            reactive::emit(Message::BrushSizeStep(1));
+        })
+        .item("Smaller", |window| {
+            reactive::emit(Message::BrushSizeStep(-1));
+        })
+        .separator()
+        .item("Round Tip", |window| {
+            reactive::emit(Message::BrushTipSelect(BrushTip::RoundHard));
+        })
+        .item("Soft Round Tip", |window| {
+            reactive::emit(Message::BrushTipSelect(BrushTip::RoundSoft));
+        })
+        .item("Square Tip", |window| {
+            reactive::emit(Message::BrushTipSelect(BrushTip::Square));
+        })
+        .item("Calligraphic Tip", |window| {
+            reactive::emit(Message::BrushTipSelect(BrushTip::Calligraphic(Calligraphic { aspect: 0.3, angle: PI / 4.0 })));
+        })
+        .item("Stamp Tip...", |window| {
+            if let Some(path) = window.open_file() {
+                reactive::emit(Message::BrushTipStamp(path));
+            }
+        })
+        .separator()
+        .check_item("Pressure Size", false, |window, checked| {
+            reactive::emit(Message::PressureSize(checked));
+        })
+        .check_item("Pressure Opacity", false, |window, checked| {
+            reactive::emit(Message::PressureOpacity(checked));
+        })
+        .check_item("Synthetic Pressure", false, |window, checked| {
+            reactive::emit(Message::SyntheticPressure(checked));
+        });
+    Menu::new("Stroke")
+        .item("No Stabilizer", |window| {
+            reactive::emit(Message::StabilizerSelect(StabilizerKind::None));
+        })
+        .item("Moving Average", |window| {
+            reactive::emit(Message::StabilizerSelect(StabilizerKind::MovingAverage(8)));
+        })
+        .item("Lazy Rope", |window| {
+            reactive::emit(Message::StabilizerSelect(StabilizerKind::LazyRope(15.0)));
+        })
+        .item("One Euro Filter", |window| {
+            reactive::emit(Message::StabilizerSelect(StabilizerKind::OneEuro { min_cutoff: 1.0, beta: 0.02 }));
+        })
+        .separator()
+        .item("No Smoothing", |window| {
+            reactive::emit(Message::SmoothingSelect(Smoothing::None));
+        })
+        .item("Catmull-Rom Smoothing", |window| {
+            reactive::emit(Message::SmoothingSelect(Smoothing::CatmullRom));
+        })
+        .item("Bezier Smoothing", |window| {
+            reactive::emit(Message::SmoothingSelect(Smoothing::Bezier));
+        });
+    let canvas = Rc::new(RefCell::new(canvas_model::CanvasModel::new(width, height)));
+    let score = Rc::new(RefCell::new(score::ScoreModel::new()));
+    let brush_size = canvas.borrow().brush_size();
+    let show_overlay = canvas.borrow().show_overlay();
+    let tool = canvas.borrow().tool();
+    let layer_name = canvas.borrow().layer_name();
+    let layer_blend = canvas.borrow().layer_blend();
+    let layer_opacity = canvas.borrow().layer_opacity();
+    let layer_clip = canvas.borrow().layer_clip();
+    let layer_masked = canvas.borrow().layer_masked();
+    let mut blend_modes = Combobox::new();
+    for &(_, name) in blend::MODES.iter() {
+        blend_modes = blend_modes.append(name);
+    }
+    let score_text = score.borrow().text();
+    // a fixed set of rows, refilled from the stack as it changes
+    let mut layer_rows = Container::new_vertical();
+    for row in canvas.borrow().layer_rows() {
+        let (visible_id, select_id) = (row.id.clone(), row.id.clone());
+        layer_rows = layer_rows.append(Container::new_horizontal()
+            .append(Checkbox::new("")
+                .bind_checked(&row.visible)
+                .on_toggle(move |checkbox| {
+                    if visible_id.get() == 0 {
+                        // a blank row stays blank
+                        checkbox.set_checked(false);
+                        return;
+                    }
+                    reactive::emit(Message::LayerVisible(visible_id.get() as u32, checkbox.checked()));
+                }))
+            .append_stretchy(Button::new("")
+                .bind_text(&row.label)
+                .on_click(move |button| {
+                    if select_id.get() != 0 {
+                        reactive::emit(Message::SelectLayer(select_id.get() as u32));
+                    }
+                })));
+    }
+    let window = Window::new("SketchBook", width as i32, height as i32, true);
+    let save_dialog = window.handler();
+    let open_dialog = window.handler();
+    let export_dialog = window.handler();
+    let import_dialog = window.handler();
+    let components = window
         .on_closing(|w| {
             reactive::quit();
             false
@@ -61,10 +256,10 @@ fn main() {
                 .set_padding(true)
                 .append(Container::new_vertical()
                     .append(Button::new("toggle")
+                        .bind_text(&tool)
                         .on_click(|button| {
                             reactive::emit(Message::BrushToggleButton);
-                        })
-                        .set_model(Rc::new(RefCell::new(HoeModel::new()))))
+                        }))
                     .append(Button::new("close stroke")
                         .on_click(|button| {
                             reactive::emit(Message::StrokeCloseButton);
@@ -73,16 +268,134 @@ fn main() {
                         .on_click(|button| {
                             reactive::emit(Message::ClearCanvasButton);
                         }))
-                    .append(Button::new("save")
+                    .append(Button::new("undo")
+                        .on_click(|button| {
+                            reactive::emit(Message::Undo);
+                        }))
+                    .append(Button::new("redo")
+                        .on_click(|button| {
+                            reactive::emit(Message::Redo);
+                        }))
+                    .append(Entry::new()
+                        .set_text(canvas::DEFAULT_TARGET_IMAGE)
+                        .on_change(|entry| {
+                            reactive::emit(Message::TargetImage(entry.text()));
+                        }))
+                    .append(Button::new("score")
                         .on_click(|button| {
                             reactive::emit(Message::OutputButton);
                         }))
-                    .append(Slider::new(0, 10)
+                    .append(Label::new("")
+                        .bind_text(&score_text)
+                        .set_model(score))
+                    .append(Checkbox::new("show overlay")
+                        .bind_checked(&show_overlay)
+                        .on_toggle(|checkbox| {
+                            reactive::emit(Message::OverlayVisible(checkbox.checked()));
+                        }))
+                    .append(Button::new("save sketch")
+                        .on_click(move |button| {
+                            if let Some(path) = save_dialog.save_file() {
+                                reactive::emit(Message::SaveDocument(path));
+                            }
+                        }))
+                    .append(Button::new("open sketch")
+                        .on_click(move |button| {
+                            if let Some(path) = open_dialog.open_file() {
+                                reactive::emit(Message::OpenDocument(path));
+                            }
+                        }))
+                    .append(Button::new("export png")
+                        .on_click(move |button| {
+                            if let Some(path) = export_dialog.save_file() {
+                                reactive::emit(Message::ExportPng(path));
+                            }
+                        }))
+                    .append(Button::new("import png")
+                        .on_click(move |button| {
+                            if let Some(path) = import_dialog.open_file() {
+                                reactive::emit(Message::ImportPngLayer(path));
+                            }
+                        }))
+                    .append(Slider::new(brush::MIN_SIZE, brush::MAX_SIZE)
                         .on_change(|slider| {
                             reactive::emit(Message::BrushSliderUpdate(slider.value()));
                         })
-                        .set_model(Rc::new(RefCell::new(PoheModel::new())))))
-                .append(Area::new(Rc::new(RefCell::new(canvas::CanvasModel::new(width, height)))))
+                        .bind_value(&brush_size)))
+                .append(Area::new(canvas))
+                .append(Container::new_vertical()
+                    .append(layer_rows)
+                    .append(Entry::new()
+                        .bind_text(&layer_name)
+                        .on_change(|entry| {
+                            reactive::emit(Message::RenameLayer(entry.text()));
+                        }))
+                    .append(blend_modes
+                        .bind_selected(&layer_blend)
+                        .on_select(|combobox| {
+                            if let Some(&(mode, _)) = blend::MODES.get(combobox.selected() as usize) {
+                                reactive::emit(Message::LayerBlendSelect(mode));
+                            }
+                        }))
+                    .append(Slider::new(0, 100)
+                        .on_change(|slider| {
+                            reactive::emit(Message::LayerOpacity(slider.value() as f64 / 100.0));
+                        })
+                        .bind_value(&layer_opacity))
+                    .append(Checkbox::new("clip to layer below")
+                        .bind_checked(&layer_clip)
+                        .on_toggle(|checkbox| {
+                            reactive::emit(Message::LayerClip(checkbox.checked()));
+                        }))
+                    .append(Checkbox::new("mask")
+                        .bind_checked(&layer_masked)
+                        .on_toggle(|checkbox| {
+                            if checkbox.checked() {
+                                reactive::emit(Message::AddLayerMask);
+                            } else {
+                                reactive::emit(Message::DeleteLayerMask);
+                            }
+                        }))
+                    .append(Button::new("mask from stroke below")
+                        .on_click(|button| {
+                            reactive::emit(Message::MaskFromClosedStroke);
+                        }))
+                    .append(Button::new("select above")
+                        .on_click(|button| {
+                            reactive::emit(Message::SelectLayerStep(1));
+                        }))
+                    .append(Button::new("select below")
+                        .on_click(|button| {
+                            reactive::emit(Message::SelectLayerStep(-1));
+                        }))
+                    .append(Button::new("move up")
+                        .on_click(|button| {
+                            reactive::emit(Message::MoveLayer(1));
+                        }))
+                    .append(Button::new("move down")
+                        .on_click(|button| {
+                            reactive::emit(Message::MoveLayer(-1));
+                        }))
+                    .append(Button::new("new layer")
+                        .on_click(|button| {
+                            reactive::emit(Message::AddLayer);
+                        }))
+                    .append(Button::new("duplicate")
+                        .on_click(|button| {
+                            reactive::emit(Message::DuplicateLayer);
+                        }))
+                    .append(Button::new("delete")
+                        .on_click(|button| {
+                            reactive::emit(Message::DeleteLayer);
+                        }))
+                    .append(Button::new("merge down")
+                        .on_click(|button| {
+                            reactive::emit(Message::MergeLayerDown);
+                        }))
+                    .append(Button::new("flatten")
+                        .on_click(|button| {
+                            reactive::emit(Message::FlattenLayers);
+                        })))
         )
         .show()
         .get_components();
@@ -1,11 +1,76 @@
-use reactive::widget::{AreaDrawParams};
+use reactive::widget::{AreaDrawParams, Routable};
+use app::brush::{BrushTip, BrushBlend};
+use app::canvas::BlendMode;
+use app::smoothing::{StabilizerKind, Smoothing};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Topic {
+    Canvas,
+    Brush,
+    Score,
+}
 #[derive(Clone, Debug)]
 pub enum Message {
     BrushToggleButton,
     StrokeCloseButton,
     ClearCanvasButton,
     OutputButton,
+    Undo,
+    Redo,
+    Save, // to the file the document was last saved to or opened from
+    SaveDocument(String),
+    OpenDocument(String),
+    ExportPng(String),
+    ImportPngLayer(String),
+    MatchScored(f64),
+    OverlayVisible(bool),
+    TargetImage(String),
     BrushSliderUpdate(i64),
+    BrushSizeStep(i64),
+    BrushTipSelect(BrushTip),
+    BrushBlendSelect(BrushBlend),
+    BrushTipStamp(String), // grayscale image to stamp with
+    StabilizerSelect(StabilizerKind),
+    SmoothingSelect(Smoothing), // applied to strokes once they are finished
+    PressureSize(bool), // pen pressure controls the brush size
+    PressureOpacity(bool),
+    SyntheticPressure(bool), // fake pressure instead of the device's
     CanvasMouseEvent{x: f64, y: f64, down: bool, up: bool, dragging: bool},
+    StartRecording(String),
+    StopRecording,
+    PlayRecording(String, f64), // log and speed, 0 for no pauses
+    // layer operations other than selecting and hiding work on the active layer
+    AddLayer, // above the active one
+    DeleteLayer,
+    DuplicateLayer,
+    MergeLayerDown,
+    FlattenLayers,
+    MoveLayer(i64), // up the stack for positive steps
+    RenameLayer(String),
+    LayerBlendSelect(BlendMode),
+    LayerOpacity(f64), // 0..1
+    LayerClip(bool), // to the layer below
+    AddLayerMask, // showing all of the layer
+    MaskFromClosedStroke, // showing the inside of the closed stroke on the layer below
+    DeleteLayerMask,
+    SelectLayer(u32), // by id
+    SelectLayerStep(i64),
+    LayerVisible(u32, bool),
 }
 
+
+impl Routable for Message {
+    type Topic = Topic;
+
+    fn topics(&self) -> Vec<Topic> {
+        match self {
+            &Message::BrushToggleButton => vec![Topic::Brush, Topic::Canvas],
+            &Message::BrushSliderUpdate(_) | &Message::BrushSizeStep(_) |
+            &Message::PressureSize(_) | &Message::PressureOpacity(_) |
+            &Message::BrushTipSelect(_) | &Message::BrushTipStamp(_) |
+            &Message::BrushBlendSelect(_) => vec![Topic::Brush],
+            &Message::MatchScored(_) => vec![Topic::Score],
+            _ => vec![Topic::Canvas],
+        }
+    }
+}
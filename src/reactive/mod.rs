@@ -1,36 +1,252 @@
 extern crate ui;
 
 pub mod widget;
+pub mod property;
 
-use std::cell::RefCell;
-use reactive::widget::Component;
-use message::Message;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use reactive::widget::{Component, Routable};
+use message::{Message, Topic};
 
 pub fn init() {
     ui::init(ui::InitOptions).unwrap();
 }
 
+enum Delivery {
+    Topic(Message),          // to every subscriber of the message's topics
+    Component(usize, Message), // to one component, by its index in COMPONENTS
+}
+
 thread_local! {
     static COMPONENTS: RefCell<Vec<Box<Component<Message>>>> = RefCell::new(Vec::new());
+    static ROUTES: RefCell<HashMap<Topic, Vec<usize>>> = RefCell::new(HashMap::new());
+    static PENDING: RefCell<VecDeque<Delivery>> = RefCell::new(VecDeque::new());
+    static DEFERRED: RefCell<Vec<Delivery>> = RefCell::new(Vec::new());
+    static HOLD: Cell<usize> = Cell::new(0);
 }
 
-pub fn emit(message: Message) {
+fn deliver(delivery: Delivery) {
+    let (mut targets, message) = match delivery {
+        Delivery::Topic(message) => {
+            let mut targets = vec![];
+            ROUTES.with(|routes| {
+                let routes = routes.borrow();
+                for topic in message.topics() {
+                    if let Some(ids) = routes.get(&topic) {
+                        targets.extend_from_slice(ids);
+                    }
+                }
+            });
+            (targets, message)
+        },
+        Delivery::Component(id, message) => (vec![id], message),
+    };
+    targets.sort();
+    targets.dedup();
     COMPONENTS.with(|cs| {
-        for c in cs.borrow_mut().iter_mut() {
+        let cs = cs.borrow();
+        for id in targets {
+            let c = &cs[id];
             let mut ui_control = c.ui_control.clone();
-            if let Ok(mut model) = c.model.try_borrow_mut() {
-                model.update(&message, &mut ui_control);
+            match c.model.try_borrow_mut() {
+                Ok(mut model) => model.update(&message, &mut ui_control),
+                // the model is busy further up the stack; try again on the next flush
+                Err(_) => DEFERRED.with(|d| d.borrow_mut().push(Delivery::Component(id, message.clone()))),
             }
         }
     });
 }
 
+fn is_free(delivery: &Delivery) -> bool {
+    match delivery {
+        &Delivery::Topic(_) => true,
+        &Delivery::Component(id, _) => COMPONENTS.with(|cs| cs.borrow()[id].model.try_borrow_mut().is_ok()),
+    }
+}
+
+// Puts the deferred deliveries whose model is free again in front of the
+// pending ones, which are newer. False when none can be made yet.
+fn retry_deferred() -> bool {
+    let ready: Vec<Delivery> = DEFERRED.with(|d| {
+        let mut d = d.borrow_mut();
+        let (ready, busy) = d.drain(..).partition(is_free);
+        *d = busy;
+        ready
+    });
+    let retried = !ready.is_empty();
+    PENDING.with(|p| {
+        let mut p = p.borrow_mut();
+        for delivery in ready.into_iter().rev() {
+            p.push_front(delivery);
+        }
+    });
+    retried
+}
+
+fn flush() {
+    if HOLD.with(|h| h.get()) > 0 {
+        return;
+    }
+    HOLD.with(|h| h.set(1));
+    retry_deferred();
+    loop {
+        match PENDING.with(|p| p.borrow_mut().pop_front()) {
+            Some(delivery) => deliver(delivery),
+            // the deliveries so far may have freed a busy model
+            None => if !retry_deferred() {
+                break;
+            },
+        }
+    }
+    HOLD.with(|h| h.set(0));
+}
+
+// Sends the message to the components subscribed to its topics. Messages
+// emitted while another one is being delivered are queued and delivered
+// after it, in order.
+pub fn emit(message: Message) {
+    PENDING.with(|p| p.borrow_mut().push_back(Delivery::Topic(message)));
+    flush();
+}
+
+// Holds back emitted messages until `f` returns. Widget callbacks that run
+// while their model is borrowed go through this, so the model is free again
+// when its own messages are delivered.
+pub fn batch<F, R>(f: F) -> R where F: FnOnce() -> R {
+    HOLD.with(|h| h.set(h.get() + 1));
+    let result = f();
+    HOLD.with(|h| h.set(h.get() - 1));
+    flush();
+    result
+}
+
 pub fn main(components: Vec<Box<Component<Message>>>) {
-    COMPONENTS.with(move |cs| { cs.borrow_mut().extend(components) });
+    register(components);
     ui::main();
 }
 
+fn register(components: Vec<Box<Component<Message>>>) {
+    ROUTES.with(|routes| {
+        let mut routes = routes.borrow_mut();
+        for (id, c) in components.iter().enumerate() {
+            for topic in c.model.borrow().subscriptions() {
+                routes.entry(topic).or_insert(vec![]).push(id);
+            }
+        }
+    });
+    COMPONENTS.with(move |cs| { cs.borrow_mut().extend(components) });
+}
+
 pub fn quit() {
     ui::quit();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefMut;
+    use std::rc::Rc;
+    use reactive::widget::{Button, HandlerType, Model, Widget};
+
+    type Seen = Rc<RefCell<Vec<String>>>;
+
+    // Writes down what it receives, emits its replies on the first message
+    // and may let go of the model HELD keeps borrowed.
+    struct Log {
+        name: &'static str,
+        topics: Vec<Topic>,
+        seen: Seen,
+        replies: Vec<Message>,
+        release: bool,
+    }
+
+    thread_local! {
+        static HELD: RefCell<Option<RefMut<'static, Log>>> = RefCell::new(None);
+    }
+
+    impl Model<Message> for Log {
+        fn update(&mut self, message: &Message, _: &mut HandlerType) {
+            self.seen.borrow_mut().push(format!("{} {:?}", self.name, message));
+            for reply in self.replies.drain(..) {
+                emit(reply);
+            }
+            if self.release {
+                HELD.with(|h| h.borrow_mut().take());
+            }
+        }
+
+        fn subscriptions(&self) -> Vec<Topic> {
+            self.topics.clone()
+        }
+    }
+
+    fn log(name: &'static str, topics: Vec<Topic>, seen: &Seen) -> Log {
+        Log { name: name, topics: topics, seen: seen.clone(), replies: vec![], release: false }
+    }
+
+    fn component(model: &Rc<RefCell<Log>>) -> Box<Component<Message>> {
+        Button::new("").set_model(model.clone()).get_components().pop().unwrap()
+    }
+
+    fn register_all(models: Vec<Log>) -> Vec<Rc<RefCell<Log>>> {
+        let models: Vec<_> = models.into_iter().map(|m| Rc::new(RefCell::new(m))).collect();
+        register(models.iter().map(component).collect());
+        models
+    }
+
+    fn taken(seen: &Seen) -> Vec<String> {
+        seen.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn messages_reach_only_their_subscribers() {
+        let seen = Seen::default();
+        register_all(vec![log("canvas", vec![Topic::Canvas], &seen), log("brush", vec![Topic::Brush], &seen)]);
+        emit(Message::Undo);
+        emit(Message::BrushSizeStep(1));
+        assert_eq!(taken(&seen), ["canvas Undo", "brush BrushSizeStep(1)"]);
+    }
+
+    #[test]
+    fn one_delivery_for_several_topics() {
+        let seen = Seen::default();
+        register_all(vec![log("both", vec![Topic::Brush, Topic::Canvas], &seen)]);
+        emit(Message::BrushToggleButton);
+        assert_eq!(taken(&seen), ["both BrushToggleButton"]);
+    }
+
+    #[test]
+    fn emits_during_update_follow_in_order() {
+        let seen = Seen::default();
+        let mut first = log("first", vec![Topic::Canvas], &seen);
+        first.replies = vec![Message::Redo, Message::MatchScored(1.0)];
+        register_all(vec![first, log("second", vec![Topic::Canvas, Topic::Score], &seen)]);
+        emit(Message::Undo);
+        assert_eq!(taken(&seen), ["first Undo", "second Undo", "first Redo", "second Redo", "second MatchScored(1.0)"]);
+    }
+
+    #[test]
+    fn batch_holds_deliveries_until_it_returns() {
+        let seen = Seen::default();
+        register_all(vec![log("canvas", vec![Topic::Canvas], &seen)]);
+        batch(|| {
+            emit(Message::Undo);
+            batch(|| emit(Message::Redo));
+            assert!(seen.borrow().is_empty());
+        });
+        assert_eq!(taken(&seen), ["canvas Undo", "canvas Redo"]);
+    }
+
+    #[test]
+    fn busy_model_gets_the_message_once_it_is_free() {
+        let seen = Seen::default();
+        let mut releasing = log("releasing", vec![Topic::Canvas], &seen);
+        releasing.release = true;
+        let models = register_all(vec![log("busy", vec![Topic::Canvas], &seen), releasing]);
+        // borrowed from outside the dispatch until the second model lets go
+        let busy: &'static Rc<RefCell<Log>> = Box::leak(Box::new(models[0].clone()));
+        HELD.with(|h| *h.borrow_mut() = Some(busy.borrow_mut()));
+        emit(Message::Undo);
+        assert_eq!(taken(&seen), ["releasing Undo", "busy Undo"]);
+    }
+}
@@ -0,0 +1,99 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+struct Inner<T> {
+    value: T,
+    observers: Vec<Box<Fn(&T)>>,
+}
+
+// A value owned by a model that widgets can be bound to. Clones share the
+// value, so the model keeps one and hands clones to the widgets; every set
+// that changes the value is pushed to all bound widgets.
+// Observers must not set the property they observe.
+pub struct Property<T> {
+    inner: Rc<RefCell<Inner<T>>>,
+}
+
+impl<T> Clone for Property<T> {
+    fn clone(&self) -> Property<T> {
+        Property {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq + 'static> Property<T> {
+    pub fn new(value: T) -> Property<T> {
+        Property {
+            inner: Rc::new(RefCell::new(Inner {
+                value: value,
+                observers: vec![],
+            })),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        self.inner.borrow().value.clone()
+    }
+
+    pub fn set(&self, value: T) {
+        if self.inner.borrow().value == value {
+            return;
+        }
+        self.inner.borrow_mut().value = value;
+        let inner = self.inner.borrow();
+        for observer in &inner.observers {
+            observer(&inner.value);
+        }
+    }
+
+    // The observer is called with the current value right away.
+    pub fn observe<F: 'static>(&self, observer: F) where F: Fn(&T) {
+        observer(&self.inner.borrow().value);
+        self.inner.borrow_mut().observers.push(Box::new(observer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An observer that writes down every value it is called with.
+    fn recorder(property: &Property<i64>) -> Rc<RefCell<Vec<i64>>> {
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        property.observe(move |v| log.borrow_mut().push(*v));
+        seen
+    }
+
+    #[test]
+    fn observe_starts_with_the_current_value() {
+        let property = Property::new(3);
+        assert_eq!(*recorder(&property).borrow(), [3]);
+    }
+
+    #[test]
+    fn set_notifies_every_observer_on_change() {
+        let property = Property::new(0);
+        let first = recorder(&property);
+        let second = recorder(&property);
+        property.set(1);
+        property.set(1);
+        property.set(2);
+        property.set(2);
+        assert_eq!(*first.borrow(), [0, 1, 2]);
+        assert_eq!(*second.borrow(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn clones_share_the_value_and_observers() {
+        let property = Property::new(String::from("a"));
+        let clone = property.clone();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        property.observe(move |v: &String| log.borrow_mut().push(v.clone()));
+        clone.set(String::from("b"));
+        assert_eq!(property.get(), "b");
+        assert_eq!(*seen.borrow(), ["a", "b"]);
+    }
+}
@@ -4,13 +4,24 @@ use std::cell::RefCell;
 
 extern crate ui;
 
+use reactive;
+use reactive::property::Property;
+
 pub trait Widget<T, M> {
     fn get_ui_control(&self) -> ui::Control;
     fn get_components(self) -> Vec<Box<Component<M>>>;
 }
 
+// Messages name the topics they belong to; models list the topics they
+// want to receive.
+pub trait Routable {
+    type Topic: Copy + Eq + ::std::hash::Hash + fmt::Debug;
+    fn topics(&self) -> Vec<Self::Topic>;
+}
+
 pub trait Model<M> {
     fn update(&mut self, message: &M, widget_handler: &mut HandlerType);
+    fn subscriptions(&self) -> Vec<M::Topic> where M: Routable;
 }
 
 pub enum HandlerType {
@@ -18,6 +29,10 @@ pub enum HandlerType {
     Slider(SliderHandler),
     Container(ContainerHandler),
     Area(AreaHandler),
+    Label(LabelHandler),
+    Checkbox(CheckboxHandler),
+    Entry(EntryHandler),
+    Combobox(ComboboxHandler),
 }
 impl Clone for HandlerType {
     fn clone(&self) -> HandlerType {
@@ -26,6 +41,10 @@ impl Clone for HandlerType {
             &HandlerType::Slider(ref h) => HandlerType::Slider(h.clone()),
             &HandlerType::Container(ref h) => HandlerType::Container(h.clone()),
             &HandlerType::Area(ref h) => HandlerType::Area(h.clone()),
+            &HandlerType::Label(ref h) => HandlerType::Label(h.clone()),
+            &HandlerType::Checkbox(ref h) => HandlerType::Checkbox(h.clone()),
+            &HandlerType::Entry(ref h) => HandlerType::Entry(h.clone()),
+            &HandlerType::Combobox(ref h) => HandlerType::Combobox(h.clone()),
         }
     }
 }
@@ -42,6 +61,10 @@ impl<M> fmt::Debug for Component<M> {
             HandlerType::Slider(_) => "slider",
             HandlerType::Container(_) => "container",
             HandlerType::Area(_) => "area",
+            HandlerType::Label(_) => "label",
+            HandlerType::Checkbox(_) => "checkbox",
+            HandlerType::Entry(_) => "entry",
+            HandlerType::Combobox(_) => "combobox",
         };
         write!(f, "Hi: {}", ty)
     }
@@ -92,6 +115,41 @@ macro_rules! define_widget {
     }
 }
 
+// Menus have to be built before the window that shows them is created
+// (with has_menubar set).
+pub struct Menu {
+    ui_menu: ui::Menu,
+}
+
+impl Menu {
+    pub fn new(name: &str) -> Menu {
+        Menu {
+            ui_menu: ui::Menu::new(name),
+        }
+    }
+
+    pub fn item<F: 'static>(self, name: &str, callback: F) -> Menu where F: Fn(&WindowHandler) -> () {
+        self.ui_menu.append_item(name).on_clicked(Box::new(move |_, ui_window| {
+            callback(&WindowHandler { ui_window: ui_window.clone() })
+        }));
+        self
+    }
+
+    pub fn check_item<F: 'static>(self, name: &str, checked: bool, callback: F) -> Menu where F: Fn(&WindowHandler, bool) -> () {
+        let item = self.ui_menu.append_check_item(name);
+        item.set_checked(checked);
+        item.on_clicked(Box::new(move |ui_item, ui_window| {
+            callback(&WindowHandler { ui_window: ui_window.clone() }, ui_item.checked())
+        }));
+        self
+    }
+
+    pub fn separator(self) -> Menu {
+        self.ui_menu.append_separator();
+        self
+    }
+}
+
 pub struct Window<M> {
     ui_window: ui::Window,
     components: Vec<Box<Component<M>>>
@@ -101,12 +159,28 @@ pub struct WindowHandler {
     ui_window: ui::Window,
 }
 
+impl Clone for WindowHandler {
+    fn clone(&self) -> WindowHandler {
+        WindowHandler {
+            ui_window: self.ui_window.clone(),
+        }
+    }
+}
+
 impl WindowHandler {
     pub fn destroy(&self) {
         unsafe {
             &self.ui_window.destroy();
         }
     }
+
+    pub fn open_file(&self) -> Option<String> {
+        ui::open_file(&self.ui_window).map(|path| (&*path).to_string())
+    }
+
+    pub fn save_file(&self) -> Option<String> {
+        ui::save_file(&self.ui_window).map(|path| (&*path).to_string())
+    }
 }
 
 impl<M> Window<M> {
@@ -123,6 +197,12 @@ impl<M> Window<M> {
         }
     }
 
+    pub fn handler(&self) -> WindowHandler {
+        WindowHandler {
+            ui_window: self.ui_window.clone(),
+        }
+    }
+
     pub fn set_title(self, title: &str) -> Window<M> {
         self.ui_window.set_title(title);
         self
@@ -200,6 +280,12 @@ impl<M> Button<M> {
         }));
         self
     }
+
+    pub fn bind_text(self, property: &Property<String>) -> Button<M> {
+        let ui_control = self.ui_control.clone();
+        property.observe(move |text| ui_control.set_text(text));
+        self
+    }
 }
 
 define_widget!(Slider, SliderHandler, ui::Slider);
@@ -217,6 +303,12 @@ impl<M> Slider<M> {
         }));
         self
     }
+
+    pub fn bind_value(self, property: &Property<i64>) -> Slider<M> {
+        let ui_control = self.ui_control.clone();
+        property.observe(move |value| ui_control.set_value(*value));
+        self
+    }
 }
 
 impl SliderHandler {
@@ -229,6 +321,167 @@ impl SliderHandler {
     }
 }
 
+define_widget!(Label, LabelHandler, ui::Label);
+impl<M> Label<M> {
+    pub fn new(text: &str) -> Label<M> {
+        Label {
+            ui_control: ui::Label::new(text),
+            components: Vec::new(),
+        }
+    }
+
+    pub fn bind_text(self, property: &Property<String>) -> Label<M> {
+        let ui_control = self.ui_control.clone();
+        property.observe(move |text| ui_control.set_text(text));
+        self
+    }
+}
+
+impl LabelHandler {
+    pub fn set_text(&self, text: &str) {
+        self.ui_control.set_text(text);
+    }
+
+    pub fn text(&self) -> String {
+        (&*self.ui_control.text()).to_string()
+    }
+}
+
+define_widget!(Checkbox, CheckboxHandler, ui::Checkbox);
+impl<M> Checkbox<M> {
+    pub fn new(text: &str) -> Checkbox<M> {
+        Checkbox {
+            ui_control: ui::Checkbox::new(text),
+            components: Vec::new(),
+        }
+    }
+
+    pub fn set_checked(self, checked: bool) -> Checkbox<M> {
+        self.ui_control.set_checked(checked);
+        self
+    }
+
+    pub fn on_toggle<F: 'static>(self, callback: F) -> Checkbox<M> where F: Fn(&CheckboxHandler) -> () {
+        self.ui_control.on_toggled(Box::new(move |ui_checkbox| {
+            callback(&mut CheckboxHandler { ui_control: ui_checkbox.clone() })
+        }));
+        self
+    }
+
+    pub fn bind_checked(self, property: &Property<bool>) -> Checkbox<M> {
+        let ui_control = self.ui_control.clone();
+        property.observe(move |checked| ui_control.set_checked(*checked));
+        self
+    }
+}
+
+impl CheckboxHandler {
+    pub fn set_checked(&self, checked: bool) {
+        self.ui_control.set_checked(checked);
+    }
+
+    pub fn checked(&self) -> bool {
+        self.ui_control.checked()
+    }
+
+    pub fn set_text(&self, text: &str) {
+        self.ui_control.set_text(text);
+    }
+}
+
+define_widget!(Entry, EntryHandler, ui::Entry);
+impl<M> Entry<M> {
+    pub fn new() -> Entry<M> {
+        Entry {
+            ui_control: ui::Entry::new(),
+            components: Vec::new(),
+        }
+    }
+
+    pub fn set_text(self, text: &str) -> Entry<M> {
+        self.ui_control.set_text(text);
+        self
+    }
+
+    pub fn set_read_only(self, read_only: bool) -> Entry<M> {
+        self.ui_control.set_read_only(read_only);
+        self
+    }
+
+    pub fn on_change<F: 'static>(self, callback: F) -> Entry<M> where F: Fn(&EntryHandler) -> () {
+        self.ui_control.on_changed(Box::new(move |ui_entry| {
+            callback(&mut EntryHandler { ui_control: ui_entry.clone() })
+        }));
+        self
+    }
+
+    // The entry is only rewritten when its text differs, so that the cursor
+    // does not jump while the user is typing.
+    pub fn bind_text(self, property: &Property<String>) -> Entry<M> {
+        let ui_control = self.ui_control.clone();
+        property.observe(move |text| {
+            if &*ui_control.text() != text.as_str() {
+                ui_control.set_text(text);
+            }
+        });
+        self
+    }
+}
+
+impl EntryHandler {
+    pub fn set_text(&self, text: &str) {
+        self.ui_control.set_text(text);
+    }
+
+    pub fn text(&self) -> String {
+        (&*self.ui_control.text()).to_string()
+    }
+}
+
+define_widget!(Combobox, ComboboxHandler, ui::Combobox);
+impl<M> Combobox<M> {
+    pub fn new() -> Combobox<M> {
+        Combobox {
+            ui_control: ui::Combobox::new(),
+            components: Vec::new(),
+        }
+    }
+
+    pub fn append(self, item: &str) -> Combobox<M> {
+        self.ui_control.append(item);
+        self
+    }
+
+    pub fn set_selected(self, index: i64) -> Combobox<M> {
+        self.ui_control.set_selected(index);
+        self
+    }
+
+    pub fn on_select<F: 'static>(self, callback: F) -> Combobox<M> where F: Fn(&ComboboxHandler) -> () {
+        self.ui_control.on_selected(Box::new(move |ui_combobox| {
+            callback(&mut ComboboxHandler { ui_control: ui_combobox.clone() })
+        }));
+        self
+    }
+
+    pub fn bind_selected(self, property: &Property<i64>) -> Combobox<M> {
+        let ui_control = self.ui_control.clone();
+        property.observe(move |index| ui_control.set_selected(*index));
+        self
+    }
+}
+
+impl ComboboxHandler {
+    pub fn set_selected(&self, index: i64) {
+        self.ui_control.set_selected(index);
+    }
+
+    // -1 when nothing is selected
+    pub fn selected(&self) -> i64 {
+        self.ui_control.selected()
+    }
+}
+
 pub struct Area<M> {
     ui_control: ui::Area,
     components: Vec<Box<Component<M>>>,
@@ -277,6 +530,8 @@ pub type AreaMouseEvent = ui::AreaMouseEvent;
 pub type AreaKeyEvent = ui::AreaKeyEvent;
 
 pub trait AreaCallbacks {
+    // called once the area exists, so that models can redraw it on their own
+    fn on_attach(&mut self, area: &AreaHandler) {}
     fn on_draw(&mut self, area: &AreaHandler, area_draw_params: &AreaDrawParams) {}
     fn on_mouse_event(&mut self, area: &AreaHandler, area_mouse_event: &AreaMouseEvent) {}
     fn on_mouse_crossed(&mut self, area: &AreaHandler, left: bool) {}
@@ -289,6 +544,7 @@ pub trait AreaCallbacks {
 impl<M: 'static> Area<M> {
     pub fn new<T>(callbacks: Rc<RefCell<T>>) -> Area<M> where T: AreaCallbacks + Model<M> + 'static {
         let ui_control = ui::Area::new(Box::new(AreaWrapper::new(callbacks.clone())));
+        callbacks.borrow_mut().on_attach(&AreaHandler { ui_control: ui_control.clone() });
         Area {
             ui_control: ui_control.clone(),
             components: vec![
@@ -315,18 +571,23 @@ impl AreaWrapper {
 
 impl ui::AreaHandler for AreaWrapper {
     fn draw(&mut self, area: &ui::Area, area_draw_params: &ui::AreaDrawParams) {
-        self.callbacks.borrow_mut().on_draw(&AreaHandler {ui_control: area.clone()}, area_draw_params);
+        let callbacks = &self.callbacks;
+        reactive::batch(|| callbacks.borrow_mut().on_draw(&AreaHandler {ui_control: area.clone()}, area_draw_params))
     }
     fn mouse_event(&mut self, area: &ui::Area, area_mouse_event: &ui::AreaMouseEvent) {
-        self.callbacks.borrow_mut().on_mouse_event(&AreaHandler {ui_control: area.clone()}, area_mouse_event);
+        let callbacks = &self.callbacks;
+        reactive::batch(|| callbacks.borrow_mut().on_mouse_event(&AreaHandler {ui_control: area.clone()}, area_mouse_event))
     }
     fn mouse_crossed(&mut self, area: &ui::Area, left: bool) {
-        self.callbacks.borrow_mut().on_mouse_crossed(&AreaHandler {ui_control: area.clone()}, left);
+        let callbacks = &self.callbacks;
+        reactive::batch(|| callbacks.borrow_mut().on_mouse_crossed(&AreaHandler {ui_control: area.clone()}, left))
     }
     fn drag_broken(&mut self, area: &ui::Area) {
-        self.callbacks.borrow_mut().on_drag_broken(&AreaHandler {ui_control: area.clone()});
+        let callbacks = &self.callbacks;
+        reactive::batch(|| callbacks.borrow_mut().on_drag_broken(&AreaHandler {ui_control: area.clone()}))
     }
     fn key_event(&mut self, area: &ui::Area, area_key_event: &ui::AreaKeyEvent) -> bool {
-        self.callbacks.borrow_mut().on_key_event(&AreaHandler {ui_control: area.clone()}, area_key_event)
+        let callbacks = &self.callbacks;
+        reactive::batch(|| callbacks.borrow_mut().on_key_event(&AreaHandler {ui_control: area.clone()}, area_key_event))
     }
 }